@@ -0,0 +1,248 @@
+//! Renders a function to a PNG file on the CPU, for thumbnails and galleries
+//! made by scripts. See `complex-render --help`.
+
+use std::{collections::HashMap, fs::File, io::BufWriter, process::ExitCode};
+
+use complex::{
+    algebra::Complex,
+    export::write_png,
+    expression::{Expr, ExprComplex},
+    palette::Palette,
+    render::{DrawMode, Viewport},
+};
+
+const USAGE: &str = "\
+usage: complex-render [options] <function>
+
+Renders f(z, c), e.g. 'z^2+c', to a PNG file.
+
+options:
+  -o, --output <file>     file to write [default: complex.png]
+  -m, --mode <mode>       domain, stability or julia [default: domain]
+      --center <z>        point at the middle of the image [default: 0]
+      --scale <x>         half the width of the view [default: 1]
+      --size <w>x<h>      size of the image in pixels [default: 512x512]
+      --max-iter <n>      iterations in the stability and julia modes
+                          [default: 40]
+  -c <z>                  c of the julia mode [default: 0]
+      --palette <name>    colours of the stability and julia modes, one of
+                          magenta, grayscale, fire, ocean, rainbow, viridis
+                          or cividis [default: magenta]
+  -p, --param <name>=<z>  value of a parameter of the function, 0 if unset
+  -h, --help              print this help
+";
+
+#[derive(Debug)]
+struct Options {
+    function: ExprComplex,
+    /// The function as written, for the metadata.
+    source: String,
+    output: String,
+    draw_mode: DrawMode,
+    viewport: Viewport,
+    max_iter: i32,
+    parameters: HashMap<String, Complex<f32>>,
+    palette: Palette,
+}
+
+/// The options in `args`, or `None` if help was asked for.
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut function = None;
+    let mut output = "complex.png".to_string();
+    let mut draw_mode = DrawMode::DomainColouring;
+    let mut viewport = Viewport {
+        center: Complex::from(0.0),
+        xscale: 1.0,
+        width: 512,
+        height: 512,
+    };
+    let mut max_iter = 40;
+    let mut parameters = HashMap::new();
+    let mut palette = Palette::default();
+    let mut args = args.into_iter();
+    let mut options_done = false;
+    while let Some(arg) = args.next() {
+        if !options_done && (arg == "-h" || arg == "--help") {
+            return Ok(None);
+        }
+        if !options_done && arg == "--" {
+            // a function may start with `-`
+            options_done = true;
+            continue;
+        }
+        if options_done || !arg.starts_with('-') {
+            if function.replace(arg).is_some() {
+                return Err("more than one function".to_string());
+            }
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {arg}"))?;
+        match arg.as_str() {
+            "-o" | "--output" => output = value,
+            "-m" | "--mode" => {
+                draw_mode = match value.as_str() {
+                    "domain" => DrawMode::DomainColouring,
+                    "stability" => DrawMode::ParameterStability,
+                    "julia" => DrawMode::Julia,
+                    _ => return Err(format!("unknown mode '{value}'")),
+                }
+            }
+            "--center" => viewport.center = constant(&value)?,
+            "--scale" => viewport.xscale = number(&value)?,
+            "--size" => {
+                let size = value
+                    .split_once('x')
+                    .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)));
+                match size {
+                    Some((width, height)) if width > 0 && height > 0 => {
+                        viewport.width = width;
+                        viewport.height = height;
+                    }
+                    _ => return Err(format!("invalid size '{value}', expected e.g. 800x600")),
+                }
+            }
+            "--max-iter" => {
+                max_iter = (value.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| format!("invalid number of iterations '{value}'"))?
+            }
+            "-c" => {
+                let c = constant(&value)?;
+                parameters.insert("c".to_string(), c);
+            }
+            "--palette" => palette = Palette::named(&value).map_err(|err| err.to_string())?,
+            "-p" | "--param" => {
+                let Some((name, z)) = value.split_once('=') else {
+                    return Err(format!("invalid parameter '{value}', expected name=value"));
+                };
+                let z = constant(z)?;
+                parameters.insert(name.trim().to_string(), z);
+            }
+            _ => return Err(format!("unknown option {arg}")),
+        }
+    }
+    let source = function.ok_or("missing function")?;
+    let function = (source.parse::<ExprComplex>())
+        .map_err(|err| format!("invalid function: {err}"))?
+        .simplify();
+    Ok(Some(Options {
+        function,
+        source,
+        output,
+        draw_mode,
+        viewport,
+        max_iter,
+        parameters,
+        palette,
+    }))
+}
+
+/// A constant expression such as `-0.5+2i`.
+fn constant(s: &str) -> Result<Complex<f32>, String> {
+    let expr = (s.parse::<ExprComplex>())
+        .map_err(|err| format!("invalid value: {err}"))?
+        .simplify();
+    match expr {
+        Expr::Constant(z) => Ok(z),
+        _ => Err(format!("'{s}' is not a constant")),
+    }
+}
+
+fn number(s: &str) -> Result<f32, String> {
+    match constant(s)? {
+        Complex { re, im } if im == 0.0 && re > 0.0 => Ok(re),
+        _ => Err(format!("'{s}' is not a positive number")),
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("complex-render: {err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let written = File::create(&options.output)
+        .map_err(|err| err.to_string())
+        .and_then(|file| {
+            write_png(
+                BufWriter::new(file),
+                &options.function,
+                &options.source,
+                options.draw_mode,
+                &options.viewport,
+                options.max_iter,
+                &options.parameters,
+                &options.palette,
+            )
+            .map_err(|err| err.to_string())
+        });
+    match written {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("complex-render: {}: {err}", options.output);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_args;
+    use complex::{algebra::Complex, palette::Palette, render::DrawMode};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn options() {
+        let options = parse_args(args(
+            "-m julia --center -0.5+i --scale 2 --size 320x200 -c 0.25 -p a=2i z^2+a*c -o j.png",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(options.function.to_string(), "z^2+a*c");
+        assert_eq!(options.source, "z^2+a*c");
+        assert_eq!(options.output, "j.png");
+        assert_eq!(options.draw_mode, DrawMode::Julia);
+        assert_eq!(options.viewport.center, Complex { re: -0.5, im: 1.0 });
+        assert_eq!(options.viewport.xscale, 2.0);
+        assert_eq!(
+            (options.viewport.width, options.viewport.height),
+            (320, 200)
+        );
+        assert_eq!(options.parameters["c"], Complex::from(0.25));
+        assert_eq!(options.parameters["a"], Complex { re: 0.0, im: 2.0 });
+        assert_eq!(options.palette, Palette::default());
+        let options = parse_args(args("--palette viridis z")).unwrap().unwrap();
+        assert_eq!(options.palette, Palette::named("viridis").unwrap());
+        assert!(parse_args(args("--help")).unwrap().is_none());
+        let options = parse_args(args("-- -z")).unwrap().unwrap();
+        assert_eq!(options.function.to_string(), "-z");
+    }
+
+    #[test]
+    fn errors() {
+        let err = |line| parse_args(args(line)).unwrap_err();
+        assert_eq!(
+            err("--size 100"),
+            "invalid size '100', expected e.g. 800x600"
+        );
+        assert_eq!(err("z --mode fractal"), "unknown mode 'fractal'");
+        assert_eq!(err("z --scale -1"), "'-1' is not a positive number");
+        assert_eq!(err("-c z"), "'z' is not a constant");
+        assert_eq!(err("z --max-iter"), "missing value for --max-iter");
+        assert_eq!(err("z z"), "more than one function");
+        assert!(err("z --palette jet").starts_with("unknown palette 'jet'"));
+        assert_eq!(err("-m julia"), "missing function");
+        assert!(err("(z").starts_with("invalid function: expected ')'"));
+    }
+}
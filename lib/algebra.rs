@@ -1,4 +1,6 @@
 use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use serde::{Deserialize, Serialize};
 pub trait Zero {
     const O: Self;
 }
@@ -7,7 +9,26 @@ pub trait One {
 }
 pub trait Group: Sized + Copy + Add<Output = Self> + Zero {}
 pub trait Ring: Group + Sub<Output = Self> + Neg<Output = Self> + One + Mul<Output = Self> {}
-pub trait Field: Ring + Div<Output = Self> {}
+pub trait Field: Ring + Div<Output = Self> {
+    /// Integer power by repeated squaring.
+    fn powi(self, n: i32) -> Self {
+        powi_by_squaring(self, n)
+    }
+}
+
+fn powi_by_squaring<F: Field>(x: F, n: i32) -> F {
+    let mut base = if n < 0 { F::U / x } else { x };
+    let mut acc = F::U;
+    let mut e = n.unsigned_abs();
+    while e > 0 {
+        if e & 1 == 1 {
+            acc = acc * base;
+        }
+        base = base * base;
+        e >>= 1;
+    }
+    acc
+}
 
 pub trait Module<R>
 where
@@ -50,8 +71,119 @@ impl Group for f32 {}
 impl Ring for f32 {}
 impl Field for f32 {}
 
+// Field of f64
+impl Zero for f64 {
+    const O: Self = 0.0;
+}
+impl One for f64 {
+    const U: Self = 1.0;
+}
+impl Group for f64 {}
+impl Ring for f64 {}
+impl Field for f64 {}
+
+/// Elementary transcendental functions.
+pub trait Transcendental: Sized {
+    fn exp(self) -> Self;
+    fn ln(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn sin(self) -> Self;
+    fn cos(self) -> Self;
+    fn tan(self) -> Self;
+    fn sinh(self) -> Self;
+    fn cosh(self) -> Self;
+    fn tanh(self) -> Self;
+    fn asin(self) -> Self;
+    fn atan(self) -> Self;
+    fn pow(self, exponent: Self) -> Self;
+}
+
+/// Ordered fields with the real elementary functions, suitable as the
+/// components of a [`Complex`] number.
+pub trait Real: Field + Transcendental + PartialOrd {
+    fn abs(self) -> Self;
+    /// `Some(n)` if `self` is exactly the integer `n`, small enough for
+    /// [`Field::powi`] to be the better way to raise to it.
+    fn as_integer(self) -> Option<i32>;
+    fn atan2(self, x: Self) -> Self;
+    fn hypot(self, other: Self) -> Self;
+}
+
+/// Values that can overflow or be undefined, e.g. after a division by zero.
+pub trait Finite {
+    /// Neither infinite nor NaN.
+    fn is_finite(&self) -> bool;
+}
+
+macro_rules! impl_real {
+    ($t:ty) => {
+        impl Finite for $t {
+            fn is_finite(&self) -> bool {
+                <$t>::is_finite(*self)
+            }
+        }
+
+        impl Transcendental for $t {
+            fn exp(self) -> Self {
+                <$t>::exp(self)
+            }
+            fn ln(self) -> Self {
+                <$t>::ln(self)
+            }
+            fn sqrt(self) -> Self {
+                <$t>::sqrt(self)
+            }
+            fn sin(self) -> Self {
+                <$t>::sin(self)
+            }
+            fn cos(self) -> Self {
+                <$t>::cos(self)
+            }
+            fn tan(self) -> Self {
+                <$t>::tan(self)
+            }
+            fn sinh(self) -> Self {
+                <$t>::sinh(self)
+            }
+            fn cosh(self) -> Self {
+                <$t>::cosh(self)
+            }
+            fn tanh(self) -> Self {
+                <$t>::tanh(self)
+            }
+            fn asin(self) -> Self {
+                <$t>::asin(self)
+            }
+            fn atan(self) -> Self {
+                <$t>::atan(self)
+            }
+            fn pow(self, exponent: Self) -> Self {
+                <$t>::powf(self, exponent)
+            }
+        }
+
+        impl Real for $t {
+            fn abs(self) -> Self {
+                <$t>::abs(self)
+            }
+            fn as_integer(self) -> Option<i32> {
+                (self.fract() == 0.0 && self.abs() <= 65536.0).then(|| self as i32)
+            }
+            fn atan2(self, x: Self) -> Self {
+                <$t>::atan2(self, x)
+            }
+            fn hypot(self, other: Self) -> Self {
+                <$t>::hypot(self, other)
+            }
+        }
+    };
+}
+
+impl_real!(f32);
+impl_real!(f64);
+
 // Complex Numbers
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Complex<T> {
     pub re: T,
     pub im: T,
@@ -187,16 +319,412 @@ where
     pub const I: Complex<T> = Complex { re: T::O, im: T::U };
 }
 
+impl<T> Finite for Complex<T>
+where
+    T: Finite,
+{
+    fn is_finite(&self) -> bool {
+        self.re.is_finite() && self.im.is_finite()
+    }
+}
+
 impl<T> Group for Complex<T> where T: Group {}
 impl<T> Ring for Complex<T> where T: Ring {}
-impl<T> Field for Complex<T> where T: Field {}
+impl<T> Field for Complex<T>
+where
+    T: Field + PartialEq,
+{
+    /// `0^n` is infinite for negative `n`, as in [`Transcendental::pow`],
+    /// rather than the NaN of `1/0`.
+    fn powi(self, n: i32) -> Self {
+        if n < 0 && self == Complex::O {
+            Complex::from(T::U / T::O)
+        } else {
+            powi_by_squaring(self, n)
+        }
+    }
+}
 impl<T> Module<T> for Complex<T> where T: Ring {}
 impl<T> VectorSpace<T> for Complex<T> where T: Field {}
 
-impl std::fmt::Display for Complex<f32> {
+impl<T> Complex<T>
+where
+    T: Real,
+{
+    /// `Some(n)` if `self` is the real integer `n`, see [`Real::as_integer`].
+    pub fn as_integer(&self) -> Option<i32> {
+        match self.im == T::O {
+            true => self.re.as_integer(),
+            false => None,
+        }
+    }
+}
+
+/// Multivalued functions return their principal value. The branch cuts are the
+/// usual ones: `ln`, `sqrt` and `pow` are cut along the negative real axis,
+/// `asin` along the real axis outside `[-1, 1]` and `atan` along the imaginary
+/// axis outside `[-i, i]`. On a cut the value is continuous from above.
+impl<T> Transcendental for Complex<T>
+where
+    T: Real,
+{
+    fn exp(self) -> Self {
+        Complex {
+            re: self.im.cos(),
+            im: self.im.sin(),
+        } * self.re.exp()
+    }
+
+    /// `ln|z| + i arg z` with `arg z` in `(-pi, pi]`.
+    fn ln(self) -> Self {
+        Complex {
+            re: self.re.hypot(self.im).ln(),
+            im: self.im.atan2(self.re),
+        }
+    }
+
+    /// The root with non-negative real part.
+    fn sqrt(self) -> Self {
+        let two = T::U + T::U;
+        let r = self.re.hypot(self.im);
+        let re = ((r + self.re) / two).sqrt();
+        let im = ((r - self.re) / two).sqrt();
+        Complex {
+            re,
+            im: if self.im < T::O { -im } else { im },
+        }
+    }
+
+    fn sin(self) -> Self {
+        Complex {
+            re: self.re.sin() * self.im.cosh(),
+            im: self.re.cos() * self.im.sinh(),
+        }
+    }
+
+    fn cos(self) -> Self {
+        Complex {
+            re: self.re.cos() * self.im.cosh(),
+            im: -(self.re.sin() * self.im.sinh()),
+        }
+    }
+
+    fn tan(self) -> Self {
+        let (re, im) = (self.re + self.re, self.im + self.im);
+        Complex {
+            re: re.sin(),
+            im: im.sinh(),
+        } / (re.cos() + im.cosh())
+    }
+
+    fn sinh(self) -> Self {
+        Complex {
+            re: self.re.sinh() * self.im.cos(),
+            im: self.re.cosh() * self.im.sin(),
+        }
+    }
+
+    fn cosh(self) -> Self {
+        Complex {
+            re: self.re.cosh() * self.im.cos(),
+            im: self.re.sinh() * self.im.sin(),
+        }
+    }
+
+    fn tanh(self) -> Self {
+        let (re, im) = (self.re + self.re, self.im + self.im);
+        Complex {
+            re: re.sinh(),
+            im: im.sin(),
+        } / (re.cosh() + im.cos())
+    }
+
+    /// `-i ln(iz + sqrt(1 - z^2))`.
+    fn asin(self) -> Self {
+        let i = Self::I;
+        -i * (i * self + (Self::U - self * self).sqrt()).ln()
+    }
+
+    /// `i/2 (ln(1 - iz) - ln(1 + iz))`.
+    fn atan(self) -> Self {
+        let i = Self::I;
+        let iz = i * self;
+        i * ((Self::U - iz).ln() - (Self::U + iz).ln()) / (T::U + T::U)
+    }
+
+    /// `exp(w ln z)`, extended with `0^0 = 1`, `0^w = 0` if `Re(w) > 0`,
+    /// infinity if `Re(w) < 0`, and NaN for the other imaginary `w`, around
+    /// which `z^w` winds without a limit. Integer exponents, which are
+    /// evaluated with [`Field::powi`] instead, agree, and so do `cpow` and
+    /// `cpowi` in `plotter.frag`.
+    fn pow(self, exponent: Self) -> Self {
+        if self == Complex::O {
+            if exponent == Complex::O {
+                Complex::U
+            } else if exponent.re > T::O {
+                Complex::O
+            } else {
+                let infinity = T::U / T::O;
+                match exponent.re < T::O {
+                    true => Complex::from(infinity),
+                    false => Complex::from(infinity * T::O),
+                }
+            }
+        } else {
+            (exponent * self.ln()).exp()
+        }
+    }
+}
+
+// Dual Numbers
+/// `re + eps ε` with `ε² = 0`. Evaluating a function at `x + ε` gives
+/// `f(x) + f'(x) ε`, that is, forward mode automatic differentiation.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub struct Dual<T> {
+    pub re: T,
+    pub eps: T,
+}
+
+impl<T> Dual<T>
+where
+    T: One,
+{
+    /// The independent variable at `re`, whose derivative is one.
+    pub fn variable(re: T) -> Self {
+        Dual { re, eps: T::U }
+    }
+}
+
+impl<T> From<T> for Dual<T>
+where
+    T: Zero,
+{
+    fn from(re: T) -> Self {
+        Dual { re, eps: T::O }
+    }
+}
+
+impl<T> Add<Self> for Dual<T>
+where
+    T: Add<Output = T>,
+{
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Dual {
+            re: self.re + rhs.re,
+            eps: self.eps + rhs.eps,
+        }
+    }
+}
+
+impl<T> Neg for Dual<T>
+where
+    T: Neg<Output = T>,
+{
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Dual {
+            re: -self.re,
+            eps: -self.eps,
+        }
+    }
+}
+
+impl<T> Sub<Self> for Dual<T>
+where
+    T: Sub<Output = T>,
+{
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Dual {
+            re: self.re - rhs.re,
+            eps: self.eps - rhs.eps,
+        }
+    }
+}
+
+impl<T> Mul<Self> for Dual<T>
+where
+    T: Copy + Add<Output = T> + Mul<Output = T>,
+{
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        Dual {
+            re: self.re * rhs.re,
+            eps: self.re * rhs.eps + self.eps * rhs.re,
+        }
+    }
+}
+
+impl<T> Div<Self> for Dual<T>
+where
+    T: Copy + Sub<Output = T> + Mul<Output = T> + Div<Output = T>,
+{
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self::Output {
+        Dual {
+            re: self.re / rhs.re,
+            eps: (self.eps * rhs.re - self.re * rhs.eps) / (rhs.re * rhs.re),
+        }
+    }
+}
+
+impl<T> Zero for Dual<T>
+where
+    T: Zero,
+{
+    const O: Dual<T> = Dual {
+        re: T::O,
+        eps: T::O,
+    };
+}
+
+impl<T> One for Dual<T>
+where
+    T: Zero + One,
+{
+    const U: Dual<T> = Dual {
+        re: T::U,
+        eps: T::O,
+    };
+}
+
+/// Also the derivative, which is infinite where the function has a vertical
+/// tangent.
+impl<T> Finite for Dual<T>
+where
+    T: Finite,
+{
+    fn is_finite(&self) -> bool {
+        self.re.is_finite() && self.eps.is_finite()
+    }
+}
+
+impl<T> Group for Dual<T> where T: Group {}
+impl<T> Ring for Dual<T> where T: Ring {}
+impl<T> Field for Dual<T> where T: Field {}
+
+impl<T> Dual<T>
+where
+    T: Copy + Mul<Output = T>,
+{
+    /// Chain rule: `f(re) + f'(re) eps ε`.
+    fn chain(self, f: T, df: T) -> Self {
+        Dual {
+            re: f,
+            eps: df * self.eps,
+        }
+    }
+}
+
+impl<T> Transcendental for Dual<T>
+where
+    T: Field + Transcendental + PartialEq,
+{
+    fn exp(self) -> Self {
+        let exp = self.re.exp();
+        self.chain(exp, exp)
+    }
+
+    fn ln(self) -> Self {
+        self.chain(self.re.ln(), T::U / self.re)
+    }
+
+    fn sqrt(self) -> Self {
+        let sqrt = self.re.sqrt();
+        self.chain(sqrt, T::U / (sqrt + sqrt))
+    }
+
+    fn sin(self) -> Self {
+        self.chain(self.re.sin(), self.re.cos())
+    }
+
+    fn cos(self) -> Self {
+        self.chain(self.re.cos(), -self.re.sin())
+    }
+
+    fn tan(self) -> Self {
+        let tan = self.re.tan();
+        self.chain(tan, T::U + tan * tan)
+    }
+
+    fn sinh(self) -> Self {
+        self.chain(self.re.sinh(), self.re.cosh())
+    }
+
+    fn cosh(self) -> Self {
+        self.chain(self.re.cosh(), self.re.sinh())
+    }
+
+    fn tanh(self) -> Self {
+        let tanh = self.re.tanh();
+        self.chain(tanh, T::U - tanh * tanh)
+    }
+
+    fn asin(self) -> Self {
+        self.chain(self.re.asin(), T::U / (T::U - self.re * self.re).sqrt())
+    }
+
+    fn atan(self) -> Self {
+        self.chain(self.re.atan(), T::U / (T::U + self.re * self.re))
+    }
+
+    /// The `ln` term of the derivative is skipped for constant exponents, so
+    /// that e.g. `x^2` is still differentiable at `x <= 0`.
+    fn pow(self, exponent: Self) -> Self {
+        let pow = self.re.pow(exponent.re);
+        let mut eps = exponent.re * self.re.pow(exponent.re - T::U) * self.eps;
+        if exponent.eps != T::O {
+            eps = eps + pow * self.re.ln() * exponent.eps;
+        }
+        Dual { re: pow, eps }
+    }
+}
+
+impl<T> Real for Dual<T>
+where
+    T: Real,
+{
+    fn abs(self) -> Self {
+        if self.re < T::O {
+            -self
+        } else {
+            self
+        }
+    }
+
+    /// Only constants, an integer power would drop the derivative with
+    /// respect to the exponent.
+    fn as_integer(self) -> Option<i32> {
+        match self.eps == T::O {
+            true => self.re.as_integer(),
+            false => None,
+        }
+    }
+
+    fn atan2(self, x: Self) -> Self {
+        Dual {
+            re: self.re.atan2(x.re),
+            eps: (x.re * self.eps - self.re * x.eps) / (x.re * x.re + self.re * self.re),
+        }
+    }
+
+    fn hypot(self, other: Self) -> Self {
+        let hypot = self.re.hypot(other.re);
+        Dual {
+            re: hypot,
+            eps: (self.re * self.eps + other.re * other.eps) / hypot,
+        }
+    }
+}
+
+impl<T> std::fmt::Display for Complex<T>
+where
+    T: Real + std::fmt::Display,
+{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let re_sign = if self.re > 0.0 { "" } else { " -" };
-        let im_sign = if self.im > 0.0 { " + " } else { " - " };
+        let re_sign = if self.re > T::O { "" } else { " -" };
+        let im_sign = if self.im > T::O { " + " } else { " - " };
         write!(
             f,
             "{}{}{}{}i",
@@ -207,3 +735,124 @@ impl std::fmt::Display for Complex<f32> {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Complex, Dual, Field, Finite, One, Transcendental, Zero};
+
+    fn assert_close(a: Complex<f32>, b: Complex<f32>) {
+        assert!((a - b).norm_sq() < 1e-10, "{a} != {b}")
+    }
+
+    #[test]
+    fn exp_ln() {
+        let z = Complex { re: 0.3, im: -1.2 };
+        assert_close(z.ln().exp(), z);
+        assert_close(z.exp().ln(), z);
+    }
+
+    #[test]
+    fn ln_branch() {
+        let z = Complex { re: -1.0, im: 0.0 };
+        assert_close(z.ln(), Complex::I * std::f32::consts::PI);
+    }
+
+    #[test]
+    fn sqrt_branch() {
+        assert_close(Complex::from(-4.0).sqrt(), Complex::I * 2.0);
+        let z = Complex {
+            re: -4.0,
+            im: -1e-30,
+        };
+        assert_close(z.sqrt(), Complex::I * -2.0);
+    }
+
+    #[test]
+    fn trig_identity() {
+        let z = Complex { re: 0.7, im: 0.4 };
+        let (s, c) = (z.sin(), z.cos());
+        assert_close(s * s + c * c, Complex::from(1.0));
+        assert_close(z.tan(), s / c);
+        assert_close(z.tanh(), z.sinh() / z.cosh());
+    }
+
+    #[test]
+    fn inverses() {
+        let z = Complex { re: 0.2, im: -0.5 };
+        assert_close(z.asin().sin(), z);
+        assert_close(z.atan().tan(), z);
+    }
+
+    #[test]
+    fn powi() {
+        let z = Complex { re: 1.0, im: 1.0 };
+        assert_eq!(z.powi(8), Complex::from(16.0));
+        assert_eq!(z.powi(-2), Complex { re: 0.0, im: -0.5 });
+        assert_eq!(Complex::<f32>::O.powi(0), Complex::from(1.0));
+        // as the general power, which the integer ones are evaluated apart from
+        let zero = Complex::<f32>::O;
+        assert_eq!(zero.powi(-2), zero.pow(Complex::from(-2.0)));
+        assert_eq!(zero.powi(-3), Complex::from(f32::INFINITY));
+        assert_eq!(zero.powi(3), zero.pow(Complex::from(3.0)));
+        assert_eq!(Complex::from(2.5).as_integer(), None);
+        assert_eq!(Complex::from(-3.0).as_integer(), Some(-3));
+        assert_eq!(Complex { re: 2.0, im: 1.0 }.as_integer(), None);
+    }
+
+    #[test]
+    fn double_precision() {
+        let z = Complex {
+            re: 1.0 + 1e-12,
+            im: 0.0,
+        };
+        assert_ne!(z, Complex::from(1.0));
+        assert!((z.ln().re - 1e-12_f64).abs() < 1e-15);
+    }
+
+    #[test]
+    fn pow() {
+        let z = Complex { re: 1.0, im: 1.0 };
+        assert_close(z.pow(Complex::from(2.0)), z * z);
+        assert_close(Complex::O.pow(Complex::O), Complex::from(1.0));
+        assert_eq!(Complex::O.pow(Complex { re: 0.5, im: 1.0 }), Complex::O);
+        let inf = Complex::O.pow(Complex { re: -2.0, im: 1.0 });
+        assert_eq!(inf, Complex::from(f64::INFINITY));
+        let nan: Complex<f64> = Complex::O.pow(Complex::I);
+        assert!(nan.re.is_nan());
+        assert!(!Complex::O.pow(Complex::from(-0.5_f32)).is_finite());
+    }
+
+    #[test]
+    fn dual_rules() {
+        let x = Dual::variable(2.0_f32);
+        assert_eq!(x * x * x, Dual { re: 8.0, eps: 12.0 });
+        assert_eq!(
+            Dual::from(1.0) / x,
+            Dual {
+                re: 0.5,
+                eps: -0.25
+            }
+        );
+        assert_eq!(x.pow(Dual::from(3.0)), Dual { re: 8.0, eps: 12.0 });
+        assert_eq!(x.exp().eps, 2.0_f32.exp());
+    }
+
+    #[test]
+    fn complex_dual() {
+        // d/dz exp(1/z) = -exp(1/z) / z^2
+        let z = Complex { re: 0.5, im: 0.75 };
+        let w = Complex {
+            re: Dual::variable(z.re),
+            im: Dual::from(z.im),
+        };
+        let fw = (Complex::from(Dual::from(1.0)) / w).exp();
+        let df = Complex {
+            re: fw.re.eps,
+            im: fw.im.eps,
+        };
+        assert_close(df, -(Complex::<f32>::U / z).exp() / (z * z));
+
+        let w = Dual::variable(z);
+        assert_close((Dual::from(Complex::U) / w).exp().eps, df);
+    }
+}
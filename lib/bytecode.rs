@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+
+use crate::{
+    algebra::Zero,
+    expression::{Definition, EvalError, Expr, Function, Operator, Variable},
+};
+
+/// An expression compiled for a stack machine, with every variable resolved
+/// to a slot. Evaluates to the same value as [`Expr::eval`], without the
+/// tree walk and the name lookups.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program<T, F, O> {
+    variables: Vec<Variable>,
+    main: Routine<T, F, O>,
+    /// The called definitions, each compiled once.
+    definitions: Vec<Routine<T, F, O>>,
+    /// The deepest the stack gets, calls included.
+    stack_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Routine<T, F, O> {
+    code: Vec<Instruction<T, F, O>>,
+    /// Number of arguments, the first locals of the routine.
+    arity: usize,
+    /// The deepest the stack gets above the arguments' base, calls included.
+    max_depth: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Instruction<T, F, O> {
+    Constant(T),
+    /// Pushes the value of a global variable, by slot.
+    Global(usize),
+    /// Pushes a parameter or let-bound value, by its position above the base
+    /// of the current routine.
+    Local(usize),
+    Function(F),
+    Operator(O),
+    /// Removes the given number of values below the top, the locals that go
+    /// out of scope.
+    Drop(usize),
+    /// Pops the condition and jumps if it is zero.
+    JumpIfZero(usize),
+    Jump(usize),
+    /// Runs a definition on the arguments on top of the stack, which are
+    /// replaced by the result.
+    Call(usize),
+}
+
+impl<T, F, O> Expr<T, F, O>
+where
+    T: Clone,
+    F: Clone,
+    O: Clone,
+{
+    /// Compiles with the global variables in `variables`, whose values are
+    /// passed in the same order to [`Program::eval`]. Fails on a free
+    /// variable that is not one of them.
+    pub fn compile(&self, variables: &[Variable]) -> Result<Program<T, F, O>, EvalError<T, F, O>> {
+        let mut compiler = Compiler {
+            variables,
+            definitions: vec![],
+            compiled: HashMap::new(),
+        };
+        let mut main = Emitter::new(0);
+        compiler.emit(self, &mut main, &mut vec![])?;
+        let main = main.finish();
+        Ok(Program {
+            variables: variables.to_vec(),
+            stack_size: main.max_depth,
+            main,
+            definitions: compiler.definitions,
+        })
+    }
+}
+
+struct Compiler<'a, T, F, O> {
+    variables: &'a [Variable],
+    definitions: Vec<Routine<T, F, O>>,
+    /// Index in `definitions` of each definition compiled so far.
+    compiled: HashMap<*const Definition<T, F, O>, usize>,
+}
+
+/// Code of a routine being compiled, with the stack depth after it.
+struct Emitter<T, F, O> {
+    code: Vec<Instruction<T, F, O>>,
+    arity: usize,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl<T, F, O> Emitter<T, F, O> {
+    fn new(arity: usize) -> Self {
+        Emitter {
+            code: vec![],
+            arity,
+            depth: arity,
+            max_depth: arity,
+        }
+    }
+
+    /// Appends an instruction that pushes `pushed` values after popping
+    /// `popped`.
+    fn push(&mut self, instruction: Instruction<T, F, O>, popped: usize, pushed: usize) {
+        self.code.push(instruction);
+        self.depth = self.depth - popped + pushed;
+        self.max_depth = self.max_depth.max(self.depth);
+    }
+
+    fn finish(mut self) -> Routine<T, F, O> {
+        // only the result is left in place of the arguments
+        if self.arity > 0 {
+            let arity = self.arity;
+            self.push(Instruction::Drop(arity), arity + 1, 1);
+        }
+        Routine {
+            code: self.code,
+            arity: self.arity,
+            max_depth: self.max_depth,
+        }
+    }
+}
+
+impl<T, F, O> Compiler<'_, T, F, O>
+where
+    T: Clone,
+    F: Clone,
+    O: Clone,
+{
+    /// Appends the code that pushes the value of `expr`. `scope` holds the
+    /// slots of the parameters and let-bound variables, innermost last.
+    fn emit<'e>(
+        &mut self,
+        expr: &'e Expr<T, F, O>,
+        out: &mut Emitter<T, F, O>,
+        scope: &mut Vec<(&'e Variable, usize)>,
+    ) -> Result<(), EvalError<T, F, O>> {
+        match expr {
+            Expr::Variable(v) => {
+                let instruction = match scope.iter().rev().find(|(local, _)| *local == v) {
+                    Some(&(_, slot)) => Instruction::Local(slot),
+                    None => match self.variables.iter().position(|global| global == v) {
+                        Some(slot) => Instruction::Global(slot),
+                        None => return Err(EvalError::UnboundVariable(v.clone())),
+                    },
+                };
+                out.push(instruction, 0, 1);
+            }
+            Expr::Constant(ct) => out.push(Instruction::Constant(ct.clone()), 0, 1),
+            Expr::Function(fun, e) => {
+                self.emit(e, out, scope)?;
+                out.push(Instruction::Function(fun.clone()), 1, 1);
+            }
+            Expr::Operator(op, left, right) => {
+                self.emit(left, out, scope)?;
+                self.emit(right, out, scope)?;
+                out.push(Instruction::Operator(op.clone()), 2, 1);
+            }
+            Expr::Let(var, e, body) => {
+                self.emit(e, out, scope)?;
+                // the bound value stays on the stack while the body runs
+                scope.push((var, out.depth - 1));
+                let body = self.emit(body, out, scope);
+                scope.pop();
+                body?;
+                out.push(Instruction::Drop(1), 2, 1);
+            }
+            Expr::Call(def, args) => {
+                for arg in args {
+                    self.emit(arg, out, scope)?;
+                }
+                let index = self.definition(def)?;
+                let routine = &self.definitions[index];
+                let base = out.depth - routine.arity;
+                out.max_depth = out.max_depth.max(base + routine.max_depth);
+                out.push(Instruction::Call(index), routine.arity, 1);
+            }
+            Expr::If(cond, then, otherwise) => {
+                self.emit(cond, out, scope)?;
+                let jump_if_zero = out.code.len();
+                out.push(Instruction::JumpIfZero(0), 1, 0);
+                self.emit(then, out, scope)?;
+                let jump = out.code.len();
+                out.push(Instruction::Jump(0), 0, 0);
+                // `otherwise` starts from the depth before `then`
+                out.depth -= 1;
+                out.code[jump_if_zero] = Instruction::JumpIfZero(out.code.len());
+                self.emit(otherwise, out, scope)?;
+                out.code[jump] = Instruction::Jump(out.code.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// Index of the compiled routine of `def`, compiling it on its first
+    /// call.
+    fn definition(&mut self, def: &Definition<T, F, O>) -> Result<usize, EvalError<T, F, O>> {
+        let key = def as *const _;
+        if let Some(&index) = self.compiled.get(&key) {
+            return Ok(index);
+        }
+        let mut out = Emitter::new(def.params.len());
+        let mut scope = def.params.iter().zip(0..).collect();
+        self.emit(&def.body, &mut out, &mut scope)?;
+        self.definitions.push(out.finish());
+        let index = self.definitions.len() - 1;
+        self.compiled.insert(key, index);
+        Ok(index)
+    }
+}
+
+impl<T, F, O> Program<T, F, O>
+where
+    T: Clone + PartialEq + Zero,
+    F: Function<T>,
+    O: Operator<T>,
+{
+    /// Evaluates with `values[k]` as the value of the `k`-th variable given
+    /// to [`Expr::compile`].
+    ///
+    /// Panics if there are fewer values than variables.
+    pub fn eval(&self, values: &[T]) -> T {
+        self.eval_with(values, &mut Vec::with_capacity(self.stack_size))
+    }
+
+    /// Like [`Program::eval`], with `stack` as working memory so that it can
+    /// be reused across evaluations. It is left empty.
+    pub fn eval_with(&self, values: &[T], stack: &mut Vec<T>) -> T {
+        assert!(
+            values.len() >= self.variables.len(),
+            "{} values for {} variables",
+            values.len(),
+            self.variables.len()
+        );
+        stack.clear();
+        self.run(&self.main, values, stack);
+        stack.pop().unwrap()
+    }
+
+    fn run(&self, routine: &Routine<T, F, O>, values: &[T], stack: &mut Vec<T>) {
+        let base = stack.len() - routine.arity;
+        let mut pc = 0;
+        while let Some(instruction) = routine.code.get(pc) {
+            pc += 1;
+            match instruction {
+                Instruction::Constant(ct) => stack.push(ct.clone()),
+                Instruction::Global(slot) => stack.push(values[*slot].clone()),
+                Instruction::Local(slot) => stack.push(stack[base + slot].clone()),
+                Instruction::Function(fun) => {
+                    let val = stack.pop().unwrap();
+                    stack.push(fun.apply(val));
+                }
+                Instruction::Operator(op) => {
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    stack.push(op.apply(left, right));
+                }
+                Instruction::Drop(n) => {
+                    let val = stack.pop().unwrap();
+                    stack.truncate(stack.len() - n);
+                    stack.push(val);
+                }
+                Instruction::JumpIfZero(target) => {
+                    if stack.pop().unwrap() == T::O {
+                        pc = *target;
+                    }
+                }
+                Instruction::Jump(target) => pc = *target,
+                Instruction::Call(index) => self.run(&self.definitions[*index], values, stack),
+            }
+        }
+    }
+}
+
+impl<T, F, O> Program<T, F, O> {
+    /// The global variables, in the order of their values.
+    pub fn variables(&self) -> &[Variable] {
+        &self.variables
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::{
+        algebra::Complex,
+        expression::{EvalError, ExprComplex, Variable},
+    };
+
+    #[test]
+    fn same_as_eval() {
+        let a = Variable::new("a");
+        let variables = [Variable::Z, Variable::C, a.clone()];
+        let exprs = [
+            "z*z+c",
+            "a*z^3-exp(1/z)+sin(c)",
+            "let w = z*z in w/(w-1)+z",
+            "let w = z in let z = 2 in w*z+a",
+            "let z = z*z in let z = z+c in z*z",
+            "2*(let w = z in w)+(let v = c in v*v)",
+            "g(w) = w*w+a; h(u, v) = g(u)-g(v)*z; h(c, z)+g(1)",
+            "g(w) = let t = w+1 in t*t; let s = z in g(s)*g(s+c)",
+            "if(abs(z) < 1, z, 1/z)",
+            "if(z > 0, let w = z in w-1, if(c >= 0, z+1, c))",
+            "g(w) = if(w < 0, -w, w); g(z-1)*g(c)",
+            "log(z)*0",
+        ];
+        let points = [
+            (0.5, 0.25),
+            (-1.5, 2.0),
+            (0.0, 0.0),
+            (3.0, -0.5),
+            (-0.25, -0.75),
+        ];
+        for input in exprs {
+            let expr = input.parse::<ExprComplex>().unwrap();
+            let program = expr.compile(&variables).unwrap();
+            for &(re, im) in &points {
+                let values = [
+                    Complex { re, im },
+                    Complex { re: im, im: re },
+                    Complex { re: 2.0, im: -1.0 },
+                ];
+                let map: HashMap<_, _> = variables.iter().cloned().zip(values).collect();
+                // compared as text so that NaN equals NaN
+                assert_eq!(
+                    format!("{:?}", program.eval(&values)),
+                    format!("{:?}", expr.eval(&map)),
+                    "{input} at {re}+{im}i"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unbound() {
+        let expr = "g(w) = w*b; g(z)".parse::<ExprComplex>().unwrap();
+        assert_eq!(
+            expr.compile(&[Variable::Z]),
+            Err(EvalError::UnboundVariable(Variable::new("b")))
+        );
+        let program = expr.compile(&[Variable::new("b"), Variable::Z]).unwrap();
+        assert_eq!(program.variables(), [Variable::new("b"), Variable::Z]);
+        assert_eq!(
+            program.eval(&[Complex::from(2.0), Complex::from(3.0)]),
+            Complex::from(6.0)
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    algebra::{Complex, Real},
+    expression::{
+        ComplexFunction, ComplexOperator, Definition, Expr, FieldFunction, FieldOperator, Variable,
+    },
+};
+
+/// Degrees of the let-bound variables and parameters, innermost last.
+type Locals<'a> = Vec<(&'a Variable, Option<u32>)>;
+
+/// Degrees of the bodies of the definitions already called, by address and
+/// degrees of the arguments.
+type Calls<T> = HashMap<
+    (
+        *const Definition<Complex<T>, ComplexFunction, ComplexOperator>,
+        Vec<Option<u32>>,
+    ),
+    Option<u32>,
+>;
+
+impl<T> Expr<Complex<T>, ComplexFunction, ComplexOperator>
+where
+    T: Real,
+{
+    /// Degree as a polynomial in the global variable `var`, the other
+    /// variables being constants, or `None` if it is not one, e.g. `1/z`,
+    /// `exp(z)` or `abs(z)`. Escape-time colouring needs it to smooth the
+    /// iteration counts, see [`crate::render::smoothing`].
+    ///
+    /// Terms that cancel out are not noticed, `z^2-z^2` has degree 2, so the
+    /// expression should be simplified first.
+    pub fn degree(&self, var: &Variable) -> Option<u32> {
+        self.degree_in(var, &mut vec![], &mut HashMap::new())
+    }
+
+    fn degree_in<'a>(
+        &'a self,
+        var: &Variable,
+        locals: &mut Locals<'a>,
+        calls: &mut Calls<T>,
+    ) -> Option<u32> {
+        use FieldOperator::{Add, Div, Mul, Sub};
+        match self {
+            Expr::Variable(v) => match locals.iter().rev().find(|(local, _)| *local == v) {
+                Some(&(_, degree)) => degree,
+                None => Some((v == var) as u32),
+            },
+            Expr::Constant(_) => Some(0),
+            Expr::Function(fun, e) => {
+                let degree = e.degree_in(var, locals, calls)?;
+                match fun {
+                    ComplexFunction::Field(FieldFunction::Neg) => Some(degree),
+                    // constant if its argument is
+                    _ => (degree == 0).then_some(0),
+                }
+            }
+            Expr::Operator(op, left, right) => {
+                let (l, r) = (
+                    left.degree_in(var, locals, calls)?,
+                    right.degree_in(var, locals, calls)?,
+                );
+                match op {
+                    ComplexOperator::Field(Add | Sub) => Some(l.max(r)),
+                    ComplexOperator::Field(Mul) => l.checked_add(r),
+                    ComplexOperator::Field(Div) => (r == 0).then_some(l),
+                    ComplexOperator::Pow if l == 0 => (r == 0).then_some(0),
+                    ComplexOperator::Pow => match &**right {
+                        Expr::Constant(w) => {
+                            let n = u32::try_from(w.as_integer()?).ok()?;
+                            l.checked_mul(n)
+                        }
+                        _ => None,
+                    },
+                    ComplexOperator::Less
+                    | ComplexOperator::LessEq
+                    | ComplexOperator::Greater
+                    | ComplexOperator::GreaterEq => (l == 0 && r == 0).then_some(0),
+                }
+            }
+            Expr::Let(v, e, body) => {
+                let degree = e.degree_in(var, locals, calls);
+                locals.push((v, degree));
+                let body = body.degree_in(var, locals, calls);
+                locals.pop();
+                body
+            }
+            Expr::Call(def, args) => {
+                let degrees: Vec<_> = (args.iter())
+                    .map(|arg| arg.degree_in(var, locals, calls))
+                    .collect();
+                let key = (Rc::as_ptr(def), degrees);
+                if let Some(&degree) = calls.get(&key) {
+                    return degree;
+                }
+                // the body sees the parameters and the globals only
+                let mut params = def.params.iter().zip(key.1.iter().copied()).collect();
+                let degree = def.body.degree_in(var, &mut params, calls);
+                calls.insert(key, degree);
+                degree
+            }
+            // a polynomial only if both branches are the same one, the
+            // condition just picks the branch
+            Expr::If(_, then, otherwise) => {
+                let degree = then.degree_in(var, locals, calls)?;
+                (otherwise.degree_in(var, locals, calls)? == degree).then_some(degree)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::expression::{call_chain, ExprComplex, Variable};
+
+    fn degree(input: &str) -> Option<u32> {
+        let expr = input.parse::<ExprComplex>().unwrap();
+        expr.simplify().degree(&Variable::Z)
+    }
+
+    #[test]
+    fn polynomials() {
+        assert_eq!(degree("c"), Some(0));
+        assert_eq!(degree("exp(c)/a+1"), Some(0));
+        assert_eq!(degree("z^2+c"), Some(2));
+        assert_eq!(degree("-z*z*z/2+z-c"), Some(3));
+        assert_eq!(degree("(z^2+c)^3"), Some(6));
+        assert_eq!(degree("z^2.0+c^z*0"), Some(2));
+        assert_eq!(degree("let w = z*z in w*w+z"), Some(4));
+        assert_eq!(degree("let z = c in z^5"), Some(0));
+        assert_eq!(degree("g(w) = w^3+z; g(z^2)+c"), Some(6));
+        assert_eq!(degree("g(z) = z; g(c)*z"), Some(1));
+        assert_eq!(degree("if(abs(z) < 1, z^2+c, z^2-c)"), Some(2));
+    }
+
+    #[test]
+    fn definitions_once() {
+        let program = call_chain(30, "w*w+z", |g| format!("{g}(w)-{g}(z)"), "z^3");
+        assert_eq!(degree(&program), Some(6));
+    }
+
+    #[test]
+    fn not_polynomials() {
+        assert_eq!(degree("1/z"), None);
+        assert_eq!(degree("z^-2+c"), None);
+        assert_eq!(degree("z^2.5"), None);
+        assert_eq!(degree("z^c"), None);
+        assert_eq!(degree("2^z"), None);
+        assert_eq!(degree("exp(z)+c"), None);
+        assert_eq!(degree("abs(z)^2"), None);
+        assert_eq!(degree("z*z < 1"), None);
+        assert_eq!(degree("if(c < 0, z^2, z^3)"), None);
+        assert_eq!(degree("let w = 1/z in w*0+z"), Some(1));
+        assert_eq!(degree("let w = 1/z in z*w"), None);
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    algebra::Ring,
+    expression::{
+        fresh_name, AsField, ComplexFunction, ComplexOperator, Definition, Expr, FieldFunction,
+        FieldOperator, Variable,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DerivativeError {
+    /// The function has no complex derivative, e.g. `Re`, `Im` or `abs`.
+    NotHolomorphic(&'static str),
+}
+
+impl std::fmt::Display for DerivativeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DerivativeError::NotHolomorphic(name) => {
+                write!(
+                    f,
+                    "'{name}' is not holomorphic, it has no complex derivative"
+                )
+            }
+        }
+    }
+}
+
+/// Derivative of `fun(u)` given `u` and its derivative `du`.
+pub trait FunctionDerivative<T, F, O> {
+    fn derivative(
+        &self,
+        u: &Expr<T, F, O>,
+        du: Expr<T, F, O>,
+    ) -> Result<Expr<T, F, O>, DerivativeError>;
+}
+
+/// Derivative of `op(u, v)` given `u`, `v` and their derivatives `du`, `dv`.
+pub trait OperatorDerivative<T, F, O> {
+    fn derivative(
+        &self,
+        u: (&Expr<T, F, O>, Expr<T, F, O>),
+        v: (&Expr<T, F, O>, Expr<T, F, O>),
+    ) -> Result<Expr<T, F, O>, DerivativeError>;
+}
+
+impl<T, F, O> Expr<T, F, O>
+where
+    T: Ring,
+    F: Clone + FunctionDerivative<T, F, O>,
+    O: Clone + OperatorDerivative<T, F, O> + From<FieldOperator>,
+{
+    /// Symbolic derivative with respect to the global variable `var`. The
+    /// result is not simplified.
+    pub fn derivative(&self, var: Variable) -> Result<Self, DerivativeError> {
+        let mut partials = Partials {
+            built: HashMap::new(),
+            names: (self.definitions().iter())
+                .map(|def| def.name.clone())
+                .chain(self.free_variables().iter().map(|v| v.name().to_string()))
+                .collect(),
+        };
+        self.derivative_by(&var, true, &mut partials)
+    }
+
+    /// `global` tells whether `var` is the global variable, which the bodies
+    /// of called definitions see, or a local one that they do not. `partials`
+    /// holds the partial derivatives of the definitions built so far.
+    fn derivative_by(
+        &self,
+        var: &Variable,
+        global: bool,
+        partials: &mut Partials<T, F, O>,
+    ) -> Result<Self, DerivativeError> {
+        use FieldOperator::{Add, Mul};
+        match self {
+            Expr::Variable(v) if v == var => Ok(Expr::Constant(T::U)),
+            Expr::Variable(_) | Expr::Constant(_) => Ok(Expr::Constant(T::O)),
+            Expr::Function(fun, u) => fun.derivative(u, u.derivative_by(var, global, partials)?),
+            Expr::Operator(op, u, v) => op.derivative(
+                (u, u.derivative_by(var, global, partials)?),
+                (v, v.derivative_by(var, global, partials)?),
+            ),
+            // (let w = e in b)' = (let w = e in ∂b/∂var) + (let w = e in ∂b/∂w) e'
+            Expr::Let(w, e, body) => {
+                let let_in = |body| Expr::let_in(w.clone(), (**e).clone(), body);
+                let chain = Expr::operator(
+                    Mul,
+                    let_in(body.derivative_by(w, false, partials)?),
+                    e.derivative_by(var, global, partials)?,
+                );
+                match w == var {
+                    // the body does not see `var`
+                    true => Ok(chain),
+                    false => Ok(Expr::operator(
+                        Add,
+                        let_in(body.derivative_by(var, global, partials)?),
+                        chain,
+                    )),
+                }
+            }
+            // g(a, b)' = ∂g/∂w(a, b) a' + ∂g/∂x(a, b) b' + ∂g/∂var(a, b)
+            Expr::Call(def, args) => {
+                let mut sum = match global && !def.params.contains(var) {
+                    true => Expr::Call(partial(def, var, true, partials)?, args.clone()),
+                    false => Expr::Constant(T::O),
+                };
+                for (param, arg) in def.params.iter().zip(args) {
+                    let term = Expr::operator(
+                        Mul,
+                        Expr::Call(partial(def, param, false, partials)?, args.clone()),
+                        arg.derivative_by(var, global, partials)?,
+                    );
+                    sum = Expr::operator(Add, sum, term);
+                }
+                Ok(sum)
+            }
+            // away from where the condition changes
+            Expr::If(cond, then, otherwise) => Ok(Expr::if_else(
+                (**cond).clone(),
+                then.derivative_by(var, global, partials)?,
+                otherwise.derivative_by(var, global, partials)?,
+            )),
+        }
+    }
+}
+
+/// The partial derivative of `def` by `var`, built on its first use and
+/// named `g_dw` after `g` and `w`, or with a suffix if that name is taken.
+fn partial<T, F, O>(
+    def: &Rc<Definition<T, F, O>>,
+    var: &Variable,
+    global: bool,
+    partials: &mut Partials<T, F, O>,
+) -> Result<Rc<Definition<T, F, O>>, DerivativeError>
+where
+    T: Ring,
+    F: Clone + FunctionDerivative<T, F, O>,
+    O: Clone + OperatorDerivative<T, F, O> + From<FieldOperator>,
+{
+    let key = (Rc::as_ptr(def), var.clone(), global);
+    if let Some(partial) = partials.built.get(&key) {
+        return Ok(partial.clone());
+    }
+    let body = def.body.derivative_by(var, global, partials)?;
+    let name = format!("{}_d{}", def.name, var.name());
+    let name = fresh_name(&name, |name| partials.names.contains(name));
+    partials.names.insert(name.clone());
+    let partial = Rc::new(Definition {
+        name,
+        params: def.params.clone(),
+        body,
+    });
+    partials.built.insert(key, partial.clone());
+    Ok(partial)
+}
+
+/// Partial derivatives by address of the definition, variable and whether
+/// it is the global one.
+type Built<T, F, O> =
+    HashMap<(*const Definition<T, F, O>, Variable, bool), Rc<Definition<T, F, O>>>;
+
+/// The partial derivatives of definitions built so far.
+struct Partials<T, F, O> {
+    built: Built<T, F, O>,
+    /// Names of the definitions and free variables, which new definitions
+    /// cannot take.
+    names: HashSet<String>,
+}
+
+impl<T, F, O> FunctionDerivative<T, F, O> for FieldFunction
+where
+    T: Clone,
+    F: Clone + AsField<FieldFunction>,
+    O: Clone + AsField<FieldOperator>,
+{
+    fn derivative(
+        &self,
+        u: &Expr<T, F, O>,
+        du: Expr<T, F, O>,
+    ) -> Result<Expr<T, F, O>, DerivativeError> {
+        use FieldOperator::*;
+        Ok(match self {
+            FieldFunction::Neg => Expr::function(FieldFunction::Neg, du),
+            // (1/u)' = -u' / u^2
+            FieldFunction::Inv => Expr::function(
+                FieldFunction::Neg,
+                Expr::operator(Div, du, Expr::operator(Mul, u.clone(), u.clone())),
+            ),
+        })
+    }
+}
+
+impl<T, F, O> OperatorDerivative<T, F, O> for FieldOperator
+where
+    T: Clone,
+    F: Clone,
+    O: Clone + AsField<FieldOperator>,
+{
+    fn derivative(
+        &self,
+        (u, du): (&Expr<T, F, O>, Expr<T, F, O>),
+        (v, dv): (&Expr<T, F, O>, Expr<T, F, O>),
+    ) -> Result<Expr<T, F, O>, DerivativeError> {
+        use FieldOperator::*;
+        Ok(match self {
+            Add => Expr::operator(Add, du, dv),
+            Sub => Expr::operator(Sub, du, dv),
+            Mul => Expr::operator(
+                Add,
+                Expr::operator(Mul, du, v.clone()),
+                Expr::operator(Mul, u.clone(), dv),
+            ),
+            Div => Expr::operator(
+                Div,
+                Expr::operator(
+                    Sub,
+                    Expr::operator(Mul, du, v.clone()),
+                    Expr::operator(Mul, u.clone(), dv),
+                ),
+                Expr::operator(Mul, v.clone(), v.clone()),
+            ),
+        })
+    }
+}
+
+impl<T> FunctionDerivative<T, ComplexFunction, ComplexOperator> for ComplexFunction
+where
+    T: Ring,
+{
+    fn derivative(
+        &self,
+        u: &Expr<T, ComplexFunction, ComplexOperator>,
+        du: Expr<T, ComplexFunction, ComplexOperator>,
+    ) -> Result<Expr<T, ComplexFunction, ComplexOperator>, DerivativeError> {
+        use ComplexFunction::*;
+        use FieldOperator::{Add, Div, Mul, Sub};
+        let of = |fun: ComplexFunction| Expr::function(fun, u.clone());
+        let one = || Expr::Constant(T::U);
+        let square = |e: Expr<_, _, _>| Expr::operator(Mul, e.clone(), e);
+        Ok(match self {
+            Field(fun) => return fun.derivative(u, du),
+            Re => return Err(DerivativeError::NotHolomorphic("Re")),
+            Im => return Err(DerivativeError::NotHolomorphic("Im")),
+            Abs => return Err(DerivativeError::NotHolomorphic("abs")),
+            Exp => Expr::operator(Mul, of(Exp), du),
+            Log => Expr::operator(Div, du, u.clone()),
+            Sqrt => Expr::operator(
+                Div,
+                du,
+                Expr::operator(Mul, Expr::Constant(T::U + T::U), of(Sqrt)),
+            ),
+            Sin => Expr::operator(Mul, of(Cos), du),
+            Cos => Expr::function(FieldFunction::Neg, Expr::operator(Mul, of(Sin), du)),
+            Tan => Expr::operator(Div, du, square(of(Cos))),
+            Sinh => Expr::operator(Mul, of(Cosh), du),
+            Cosh => Expr::operator(Mul, of(Sinh), du),
+            Tanh => Expr::operator(Div, du, square(of(Cosh))),
+            Asin => Expr::operator(
+                Div,
+                du,
+                Expr::function(Sqrt, Expr::operator(Sub, one(), square(u.clone()))),
+            ),
+            Atan => Expr::operator(Div, du, Expr::operator(Add, one(), square(u.clone()))),
+        })
+    }
+}
+
+impl<T> OperatorDerivative<T, ComplexFunction, ComplexOperator> for ComplexOperator
+where
+    T: Ring + PartialEq,
+{
+    fn derivative(
+        &self,
+        (u, du): (
+            &Expr<T, ComplexFunction, ComplexOperator>,
+            Expr<T, ComplexFunction, ComplexOperator>,
+        ),
+        (v, dv): (
+            &Expr<T, ComplexFunction, ComplexOperator>,
+            Expr<T, ComplexFunction, ComplexOperator>,
+        ),
+    ) -> Result<Expr<T, ComplexFunction, ComplexOperator>, DerivativeError> {
+        use FieldOperator::{Add, Div, Mul, Sub};
+        match self {
+            ComplexOperator::Field(op) => op.derivative((u, du), (v, dv)),
+            // piecewise constant
+            ComplexOperator::Less
+            | ComplexOperator::LessEq
+            | ComplexOperator::Greater
+            | ComplexOperator::GreaterEq => Ok(Expr::Constant(T::O)),
+            // constant exponent: (u^v)' = v u^(v-1) u', which unlike the general
+            // rule below stays finite at u = 0
+            ComplexOperator::Pow if dv == Expr::Constant(T::O) => Ok(Expr::operator(
+                Mul,
+                Expr::operator(
+                    Mul,
+                    v.clone(),
+                    Expr::operator(
+                        ComplexOperator::Pow,
+                        u.clone(),
+                        Expr::operator(Sub, v.clone(), Expr::Constant(T::U)),
+                    ),
+                ),
+                du,
+            )),
+            // (u^v)' = u^v (v' log(u) + v u' / u)
+            ComplexOperator::Pow => Ok(Expr::operator(
+                Mul,
+                Expr::operator(ComplexOperator::Pow, u.clone(), v.clone()),
+                Expr::operator(
+                    Add,
+                    Expr::operator(Mul, dv, Expr::function(ComplexFunction::Log, u.clone())),
+                    Expr::operator(Div, Expr::operator(Mul, v.clone(), du), u.clone()),
+                ),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::DerivativeError;
+    use crate::{
+        algebra::{Complex, Transcendental},
+        expression::{call_chain, ExprComplex, Variable},
+    };
+
+    fn derivative_at(input: &str, z: Complex<f32>) -> Complex<f32> {
+        let mut values = HashMap::new();
+        values.insert(Variable::Z, z);
+        values.insert(Variable::C, Complex::I);
+        input
+            .parse::<ExprComplex>()
+            .unwrap()
+            .derivative(Variable::Z)
+            .unwrap()
+            .eval(&values)
+    }
+
+    fn assert_close(a: Complex<f32>, b: Complex<f32>) {
+        assert!((a - b).norm_sq() < 1e-8, "{a} != {b}")
+    }
+
+    #[test]
+    fn polynomial() {
+        let z = Complex { re: 0.5, im: -1.5 };
+        assert_close(derivative_at("z*z*z-c", z), z * z * 3.0);
+    }
+
+    #[test]
+    fn quotient() {
+        let z = Complex { re: 0.5, im: -1.5 };
+        let one = Complex::from(1.0);
+        assert_close(
+            derivative_at("(z*z+1)/(z*z-1)", z),
+            -z * 4.0 / ((z * z - one) * (z * z - one)),
+        );
+        assert_close(derivative_at("inv(z)", z), -one / (z * z));
+    }
+
+    #[test]
+    fn transcendental() {
+        let z = Complex { re: 0.3, im: 0.2 };
+        let one = Complex::from(1.0);
+        assert_close(derivative_at("exp(1/z)", z), -(one / z).exp() / (z * z));
+        assert_close(derivative_at("log(z)", z), one / z);
+        assert_close(derivative_at("sqrt(z)", z), one / (z.sqrt() * 2.0));
+        assert_close(derivative_at("tan(z)", z), one / (z.cos() * z.cos()));
+        assert_close(derivative_at("tanh(z)", z), one / (z.cosh() * z.cosh()));
+        assert_close(derivative_at("asin(z)", z), one / (one - z * z).sqrt());
+        assert_close(derivative_at("atan(z)", z), one / (one + z * z));
+        assert_close(derivative_at("-cos(z)", z), z.sin());
+    }
+
+    #[test]
+    fn pow() {
+        let z = Complex { re: 0.3, im: 0.2 };
+        assert_close(
+            derivative_at("pow(z,3)", Complex::from(0.0)),
+            Complex::from(0.0),
+        );
+        assert_close(derivative_at("pow(z,3)", z), z * z * 3.0);
+        assert_close(
+            derivative_at("pow(z,z)", z),
+            z.pow(z) * (z.ln() + Complex::from(1.0)),
+        );
+    }
+
+    #[test]
+    fn not_holomorphic() {
+        let expr = "z*Re(z)".parse::<ExprComplex>().unwrap();
+        assert_eq!(
+            expr.derivative(Variable::Z),
+            Err(DerivativeError::NotHolomorphic("Re"))
+        );
+    }
+
+    #[test]
+    fn let_and_call() {
+        let z = Complex { re: 0.3, im: 0.2 };
+        let one = Complex::from(1.0);
+        let w = z * z + one;
+        assert_close(
+            derivative_at("let w = z*z+1 in w*w+z", z),
+            w * z * 4.0 + one,
+        );
+        assert_close(derivative_at("let z = z*z in z*z", z), z * z * z * 4.0);
+        assert_close(derivative_at("g(w) = w*w+z; g(2*z)", z), z * 8.0 + one);
+        assert_close(
+            derivative_at("g(a, b) = a*b; f(z) = g(z, exp(z))", z),
+            z.exp() * (z + one),
+        );
+    }
+
+    #[test]
+    fn definitions_differentiated_once() {
+        let program = call_chain(30, "w*w+z", |g| format!("{g}(w)*{g}(z)"), "z");
+        let expr = program.parse::<ExprComplex>().unwrap();
+        let derivative = expr.derivative(Variable::Z).unwrap();
+        // the partial derivatives by the parameter and by `z` of each, and
+        // the definitions called by the others, kept by the product rule
+        assert_eq!(derivative.definitions().len(), 2 * 30 + 29);
+    }
+
+    #[test]
+    fn partial_names() {
+        let expr = "g(w) = w*z; g_dw_2(w) = w; g(z)+g_dw_2(z)*g_dw"
+            .parse::<ExprComplex>()
+            .unwrap();
+        let derivative = expr.derivative(Variable::Z).unwrap();
+        let names: Vec<_> = (derivative.definitions().iter())
+            .map(|def| def.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            ["g_dz", "g_dw_3", "g_dw_2_dz", "g_dw_2_dw", "g_dw_2"]
+        );
+        // and it reads back
+        let printed = derivative.to_string();
+        assert_eq!(printed.parse::<ExprComplex>(), Ok(derivative));
+    }
+
+    #[test]
+    fn if_else() {
+        let z = Complex { re: 0.3, im: 0.2 };
+        assert_close(derivative_at("if(abs(z) < 1, z*z, exp(z))", z), z * 2.0);
+        assert_close(derivative_at("if(abs(z) > 1, z*z, exp(z))", z), z.exp());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{collections::HashMap, io::Write};
+
+use png::{BitDepth, ColorType, Encoder, EncodingError};
+
+use crate::{
+    algebra::Complex,
+    expression::ExprComplex,
+    palette::Palette,
+    render::{check_max_iter, render_rows, DrawMode, RenderError, Viewport},
+};
+
+/// Rows rendered at a time, so that memory stays bounded whatever the size.
+const BAND_HEIGHT: u32 = 64;
+
+#[derive(Debug)]
+pub enum ExportError {
+    /// The view cannot be drawn, nothing was written.
+    Render(RenderError),
+    Png(EncodingError),
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::Render(err) => write!(f, "{err}"),
+            ExportError::Png(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<RenderError> for ExportError {
+    fn from(err: RenderError) -> Self {
+        ExportError::Render(err)
+    }
+}
+
+impl From<EncodingError> for ExportError {
+    fn from(err: EncodingError) -> Self {
+        ExportError::Png(err)
+    }
+}
+
+/// Renders on the CPU, see [`crate::render::render`], and writes a PNG to
+/// `w`, band by band. The view is recorded in text chunks, see [`metadata`],
+/// with `source` the function as written.
+#[allow(clippy::too_many_arguments)]
+pub fn write_png<W: Write>(
+    w: W,
+    function: &ExprComplex,
+    source: &str,
+    draw_mode: DrawMode,
+    viewport: &Viewport,
+    max_iter: i32,
+    parameters: &HashMap<String, Complex<f32>>,
+    palette: &Palette,
+) -> Result<(), ExportError> {
+    check_max_iter(max_iter)?;
+    let mut encoder = Encoder::new(w, viewport.width, viewport.height);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(BitDepth::Eight);
+    let texts = metadata(source, draw_mode, viewport, max_iter, parameters, palette);
+    for (keyword, text) in texts {
+        // UTF-8, as names and functions need not be Latin-1
+        encoder.add_itxt_chunk(keyword.to_string(), text)?;
+    }
+    let mut writer = encoder.write_header()?;
+    let mut stream = writer.stream_writer()?;
+    for top in (0..viewport.height).step_by(BAND_HEIGHT as usize) {
+        let rows = top..(top + BAND_HEIGHT).min(viewport.height);
+        let pixels = render_rows(
+            function, draw_mode, viewport, max_iter, parameters, palette, rows,
+        )?;
+        stream.write_all(&pixels).map_err(EncodingError::from)?;
+    }
+    Ok(stream.finish()?)
+}
+
+/// Keywords and texts describing the view, with values written as the parser
+/// reads them, e.g. `("center", "0.5-0.25i")`. `function` is `source`, the
+/// function as the user wrote it, `parameters` has one `name = value` line
+/// per parameter, in name order, and `palette` is the palette as JSON.
+pub fn metadata(
+    source: &str,
+    draw_mode: DrawMode,
+    viewport: &Viewport,
+    max_iter: i32,
+    parameters: &HashMap<String, Complex<f32>>,
+    palette: &Palette,
+) -> Vec<(&'static str, String)> {
+    let complex = |z: Complex<f32>| ExprComplex::Constant(z).to_string();
+    let mut parameters: Vec<_> = parameters.iter().collect();
+    parameters.sort_by_key(|(name, _)| *name);
+    let parameters: Vec<_> = (parameters.into_iter())
+        .map(|(name, &value)| format!("{name} = {}", complex(value)))
+        .collect();
+    vec![
+        ("Software", "complex".to_string()),
+        ("function", source.to_string()),
+        ("draw_mode", format!("{draw_mode:?}")),
+        ("center", complex(viewport.center)),
+        ("xscale", viewport.xscale.to_string()),
+        ("max_iter", max_iter.to_string()),
+        ("parameters", parameters.join("\n")),
+        ("palette", serde_json::to_string(palette).unwrap()),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use png::Decoder;
+
+    use super::{write_png, ExportError};
+    use crate::{
+        algebra::Complex,
+        expression::ExprComplex,
+        palette::Palette,
+        render::{render, DrawMode, RenderError, Viewport},
+    };
+
+    #[test]
+    fn bands_and_metadata() {
+        // as written, not as simplified
+        let source = "g(w) = w^2;  g(z) + a*c*1";
+        let function = source.parse::<ExprComplex>().unwrap().simplify();
+        // more rows than a band, and not a multiple of it
+        let viewport = Viewport {
+            center: Complex { re: 0.5, im: -0.25 },
+            xscale: 1.5,
+            width: 7,
+            height: 150,
+        };
+        let mut parameters = HashMap::new();
+        parameters.insert("c".to_string(), Complex::from(0.25));
+        parameters.insert("a".to_string(), Complex { re: 1.0, im: 2.0 });
+        let mut palette = Palette::named("fire").unwrap();
+        palette.cyclic = true;
+        let mut png = vec![];
+        write_png(
+            &mut png,
+            &function,
+            source,
+            DrawMode::Julia,
+            &viewport,
+            30,
+            &parameters,
+            &palette,
+        )
+        .unwrap();
+
+        let mut reader = Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(
+            pixels,
+            render(
+                &function,
+                DrawMode::Julia,
+                &viewport,
+                30,
+                &parameters,
+                &palette
+            )
+            .unwrap()
+        );
+        let texts: HashMap<_, _> = (reader.info().utf8_text.iter())
+            .map(|chunk| (chunk.keyword.as_str(), chunk.get_text().unwrap()))
+            .collect();
+        assert_eq!(texts["function"], source);
+        assert_eq!(texts["draw_mode"], "Julia");
+        assert_eq!(texts["center"], "0.5-0.25i");
+        assert_eq!(texts["xscale"], "1.5");
+        assert_eq!(texts["max_iter"], "30");
+        assert_eq!(texts["parameters"], "a = 1+2i\nc = 0.25");
+        let read = serde_json::from_str::<Palette>(&texts["palette"]).unwrap();
+        assert_eq!(read, palette);
+        // enough to render it again
+        let read = texts["function"].parse::<ExprComplex>().unwrap();
+        assert_eq!(read.simplify(), function);
+        let center = texts["center"].parse::<ExprComplex>().unwrap();
+        assert_eq!(center.simplify(), ExprComplex::Constant(viewport.center));
+    }
+
+    #[test]
+    fn max_iter() {
+        let function = "z^2+c".parse::<ExprComplex>().unwrap();
+        let viewport = Viewport {
+            center: Complex::from(0.0),
+            xscale: 1.0,
+            width: 4,
+            height: 4,
+        };
+        for max_iter in [0, -5] {
+            let mut png = vec![];
+            let written = write_png(
+                &mut png,
+                &function,
+                "z^2+c",
+                DrawMode::ParameterStability,
+                &viewport,
+                max_iter,
+                &HashMap::new(),
+                &Palette::default(),
+            );
+            assert!(matches!(
+                written,
+                Err(ExportError::Render(RenderError::MaxIter(n))) if n == max_iter
+            ));
+            assert!(png.is_empty());
+        }
+    }
+}
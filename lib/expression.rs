@@ -1,5 +1,10 @@
-use crate::{algebra::Complex, algebra::Field};
-use std::collections::HashMap;
+use crate::algebra::{Complex, Field, Finite, One, Real, Transcendental, Zero};
+use std::{
+    borrow::{Borrow, Cow},
+    collections::{BTreeSet, HashMap, HashSet},
+    hash::Hash,
+    rc::Rc,
+};
 
 pub trait Function<T> {
     fn apply(&self, val: T) -> T;
@@ -15,33 +20,320 @@ pub enum Expr<T, F, O> {
     Constant(T),
     Function(F, Box<Expr<T, F, O>>),
     Operator(O, Box<Expr<T, F, O>>, Box<Expr<T, F, O>>),
+    /// `let var = bound in body`.
+    Let(Variable, Box<Expr<T, F, O>>, Box<Expr<T, F, O>>),
+    /// A call of a user-defined function, shared by all its calls.
+    Call(Rc<Definition<T, F, O>>, Vec<Expr<T, F, O>>),
+    /// `if(cond, then, else)`: `then` where `cond` is non-zero, else `else`.
+    /// Only the chosen branch is evaluated.
+    If(Box<Expr<T, F, O>>, Box<Expr<T, F, O>>, Box<Expr<T, F, O>>),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum Variable {
-    Z,
-    C,
+/// A user-defined function `name(params) = body`. The body sees its
+/// parameters and the global variables, never the locals of a caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition<T, F, O> {
+    pub name: String,
+    pub params: Vec<Variable>,
+    pub body: Expr<T, F, O>,
+}
+
+/// `name`, or if it is taken the first of `name_2`, `name_3`... that is not.
+pub(crate) fn fresh_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    let mut fresh = name.to_string();
+    let mut k = 1;
+    while taken(&fresh) {
+        k += 1;
+        fresh = format!("{name}_{k}");
+    }
+    fresh
+}
+
+/// Function or operator sets that contain the field ones, so that generic
+/// passes can build and recognise `+`, `-`, `*`, `/`, negation and inversion.
+pub trait AsField<K>: From<K> {
+    fn as_field(&self) -> Option<&K>;
+}
+
+impl<T, F, O> Expr<T, F, O> {
+    pub fn function(fun: impl Into<F>, e: Self) -> Self {
+        Expr::Function(fun.into(), Box::new(e))
+    }
+
+    pub fn operator(op: impl Into<O>, left: Self, right: Self) -> Self {
+        Expr::Operator(op.into(), Box::new(left), Box::new(right))
+    }
+
+    pub fn let_in(var: Variable, bound: Self, body: Self) -> Self {
+        Expr::Let(var, Box::new(bound), Box::new(body))
+    }
+
+    pub fn if_else(cond: Self, then: Self, otherwise: Self) -> Self {
+        Expr::If(Box::new(cond), Box::new(then), Box::new(otherwise))
+    }
+}
+
+/// A named variable. The plotter iterates over `z` and `c`, any other name is
+/// a parameter set from outside.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Variable(Cow<'static, str>);
+
+impl Variable {
+    pub const Z: Variable = Variable(Cow::Borrowed("z"));
+    pub const C: Variable = Variable(Cow::Borrowed("c"));
+
+    pub fn new(name: impl Into<String>) -> Self {
+        Variable(Cow::Owned(name.into()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Hashes and compares as its name, so maps keyed by `Variable` can be
+/// looked up by name.
+impl Borrow<str> for Variable {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Values of let-bound variables and parameters, innermost last.
+type Locals<'a, T> = Vec<(&'a Variable, T)>;
+
+impl<T, F, O> Expr<T, F, O> {
+    /// The global variables the expression depends on, in name order. Those
+    /// bound by a `let` or a definition are not free, while the free
+    /// variables of a called definition are.
+    pub fn free_variables(&self) -> BTreeSet<&Variable> {
+        let mut vars = BTreeSet::new();
+        self.collect_variables(&mut vec![], &mut HashSet::new(), &mut vars);
+        vars
+    }
+
+    /// The definitions the expression calls, directly or through other
+    /// definitions, each once and after those its body calls.
+    pub fn definitions(&self) -> Vec<&Rc<Definition<T, F, O>>> {
+        let mut defs = vec![];
+        self.collect_definitions(&mut HashSet::new(), &mut defs);
+        defs
+    }
+
+    fn collect_definitions<'a>(
+        &'a self,
+        seen: &mut HashSet<*const Definition<T, F, O>>,
+        defs: &mut Vec<&'a Rc<Definition<T, F, O>>>,
+    ) {
+        match self {
+            Expr::Variable(_) | Expr::Constant(_) => {}
+            Expr::Function(_, e) => e.collect_definitions(seen, defs),
+            Expr::Operator(_, left, right) | Expr::Let(_, left, right) => {
+                left.collect_definitions(seen, defs);
+                right.collect_definitions(seen, defs);
+            }
+            Expr::Call(def, args) => {
+                for arg in args {
+                    arg.collect_definitions(seen, defs);
+                }
+                if seen.insert(Rc::as_ptr(def)) {
+                    def.body.collect_definitions(seen, defs);
+                    defs.push(def);
+                }
+            }
+            Expr::If(cond, then, otherwise) => {
+                cond.collect_definitions(seen, defs);
+                then.collect_definitions(seen, defs);
+                otherwise.collect_definitions(seen, defs);
+            }
+        }
+    }
+
+    /// `seen` holds the definitions whose bodies were visited, which add the
+    /// same variables at every call.
+    fn collect_variables<'a>(
+        &'a self,
+        bound: &mut Vec<&'a Variable>,
+        seen: &mut HashSet<*const Definition<T, F, O>>,
+        vars: &mut BTreeSet<&'a Variable>,
+    ) {
+        match self {
+            Expr::Variable(v) => {
+                if !bound.contains(&v) {
+                    vars.insert(v);
+                }
+            }
+            Expr::Constant(_) => {}
+            Expr::Function(_, e) => e.collect_variables(bound, seen, vars),
+            Expr::Operator(_, left, right) => {
+                left.collect_variables(bound, seen, vars);
+                right.collect_variables(bound, seen, vars);
+            }
+            Expr::Let(var, e, body) => {
+                e.collect_variables(bound, seen, vars);
+                bound.push(var);
+                body.collect_variables(bound, seen, vars);
+                bound.pop();
+            }
+            Expr::Call(def, args) => {
+                for arg in args {
+                    arg.collect_variables(bound, seen, vars);
+                }
+                if seen.insert(Rc::as_ptr(def)) {
+                    let mut params = def.params.iter().collect();
+                    def.body.collect_variables(&mut params, seen, vars);
+                }
+            }
+            Expr::If(cond, then, otherwise) => {
+                cond.collect_variables(bound, seen, vars);
+                then.collect_variables(bound, seen, vars);
+                otherwise.collect_variables(bound, seen, vars);
+            }
+        }
+    }
+}
+
+fn lookup<'a, K, T>(var: &Variable, t: &'a HashMap<K, T>, locals: &'a Locals<T>) -> Option<&'a T>
+where
+    K: Borrow<str> + Eq + Hash,
+{
+    match locals.iter().rev().find(|(local, _)| *local == var) {
+        Some((_, val)) => Some(val),
+        None => t.get(var.name()),
+    }
 }
 
 impl<T, F, O> Expr<T, F, O>
 where
-    T: Clone,
+    T: Clone + PartialEq + Zero,
     F: Function<T>,
     O: Operator<T>,
 {
-    pub fn eval(&self, t: &HashMap<Variable, T>) -> T {
+    /// Evaluates with the variable values in `t`, keyed by name.
+    ///
+    /// Panics if a variable of the expression has no value, see
+    /// [`Expr::try_eval`].
+    pub fn eval<K>(&self, t: &HashMap<K, T>) -> T
+    where
+        K: Borrow<str> + Eq + Hash,
+    {
+        self.eval_in(t, &mut vec![])
+    }
+
+    fn eval_in<'a, K>(&'a self, t: &HashMap<K, T>, locals: &mut Locals<'a, T>) -> T
+    where
+        K: Borrow<str> + Eq + Hash,
+    {
         match &self {
-            Expr::Variable(v) => t.get(v).expect("a").clone(),
+            Expr::Variable(v) => match lookup(v, t, locals) {
+                Some(val) => val.clone(),
+                None => panic!("no value for variable '{}'", v.name()),
+            },
             Expr::Constant(ct) => ct.clone(),
             Expr::Function(fun, exp) => {
-                let val = exp.eval(t);
+                let val = exp.eval_in(t, locals);
                 fun.apply(val)
             }
             Expr::Operator(op, left, right) => {
-                let left = left.eval(t);
-                let right = right.eval(t);
+                let left = left.eval_in(t, locals);
+                let right = right.eval_in(t, locals);
                 op.apply(left, right)
             }
+            Expr::Let(var, e, body) => {
+                let val = e.eval_in(t, locals);
+                locals.push((var, val));
+                let val = body.eval_in(t, locals);
+                locals.pop();
+                val
+            }
+            Expr::Call(def, args) => {
+                let args: Vec<_> = args.iter().map(|arg| arg.eval_in(t, locals)).collect();
+                let mut params = def.params.iter().zip(args).collect();
+                def.body.eval_in(t, &mut params)
+            }
+            Expr::If(cond, then, otherwise) => match cond.eval_in(t, locals) != T::O {
+                true => then.eval_in(t, locals),
+                false => otherwise.eval_in(t, locals),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError<T, F, O> {
+    UnboundVariable(Variable),
+    /// The innermost subexpression whose value is infinite or NaN.
+    NonFinite(Expr<T, F, O>),
+}
+
+impl<T, F, O> std::fmt::Display for EvalError<T, F, O>
+where
+    Expr<T, F, O>: std::fmt::Display,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::UnboundVariable(var) => write!(f, "no value for variable '{var}'"),
+            EvalError::NonFinite(e) => write!(f, "'{e}' is not finite"),
+        }
+    }
+}
+
+impl<T, F, O> Expr<T, F, O>
+where
+    T: Clone + PartialEq + Zero + Finite,
+    F: Clone + Function<T>,
+    O: Clone + Operator<T>,
+{
+    /// Like [`Expr::eval`], but fails on unbound variables and on the first
+    /// value, bottom up, that is infinite or NaN.
+    pub fn try_eval<K>(&self, t: &HashMap<K, T>) -> Result<T, EvalError<T, F, O>>
+    where
+        K: Borrow<str> + Eq + Hash,
+    {
+        self.try_eval_in(t, &mut vec![])
+    }
+
+    fn try_eval_in<'a, K>(
+        &'a self,
+        t: &HashMap<K, T>,
+        locals: &mut Locals<'a, T>,
+    ) -> Result<T, EvalError<T, F, O>>
+    where
+        K: Borrow<str> + Eq + Hash,
+    {
+        let val = match &self {
+            Expr::Variable(v) => lookup(v, t, locals)
+                .cloned()
+                .ok_or_else(|| EvalError::UnboundVariable(v.clone()))?,
+            Expr::Constant(ct) => ct.clone(),
+            Expr::Function(fun, exp) => fun.apply(exp.try_eval_in(t, locals)?),
+            Expr::Operator(op, left, right) => {
+                let left = left.try_eval_in(t, locals)?;
+                op.apply(left, right.try_eval_in(t, locals)?)
+            }
+            Expr::Let(var, e, body) => {
+                let val = e.try_eval_in(t, locals)?;
+                locals.push((var, val));
+                let val = body.try_eval_in(t, locals);
+                locals.pop();
+                val?
+            }
+            Expr::Call(def, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.try_eval_in(t, locals))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut params = def.params.iter().zip(args).collect();
+                def.body.try_eval_in(t, &mut params)?
+            }
+            Expr::If(cond, then, otherwise) => match cond.try_eval_in(t, locals)? != T::O {
+                true => then.try_eval_in(t, locals)?,
+                false => otherwise.try_eval_in(t, locals)?,
+            },
+        };
+        match val.is_finite() {
+            true => Ok(val),
+            false => Err(EvalError::NonFinite(self.clone())),
         }
     }
 }
@@ -68,6 +360,12 @@ where
     }
 }
 
+impl AsField<FieldOperator> for FieldOperator {
+    fn as_field(&self) -> Option<&FieldOperator> {
+        Some(self)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FieldFunction {
     Neg,
@@ -86,21 +384,221 @@ where
     }
 }
 
+impl AsField<FieldFunction> for FieldFunction {
+    fn as_field(&self) -> Option<&FieldFunction> {
+        Some(self)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ComplexFunction {
+    Field(FieldFunction),
     Re,
     Im,
     Abs,
+    Exp,
+    Log,
+    Sqrt,
+    Sin,
+    Cos,
+    Tan,
+    Sinh,
+    Cosh,
+    Tanh,
+    Asin,
+    Atan,
 }
 
-impl Function<Complex<f32>> for ComplexFunction {
-    fn apply(&self, val: Complex<f32>) -> Complex<f32> {
+impl<T> Function<Complex<T>> for ComplexFunction
+where
+    T: Real,
+{
+    fn apply(&self, val: Complex<T>) -> Complex<T> {
         match &self {
+            ComplexFunction::Field(fun) => fun.apply(val),
             ComplexFunction::Re => val.re.into(),
             ComplexFunction::Im => val.im.into(),
-            ComplexFunction::Abs => f32::sqrt(val.norm_sq()).into(),
+            ComplexFunction::Abs => val.re.hypot(val.im).into(),
+            ComplexFunction::Exp => val.exp(),
+            ComplexFunction::Log => val.ln(),
+            ComplexFunction::Sqrt => val.sqrt(),
+            ComplexFunction::Sin => val.sin(),
+            ComplexFunction::Cos => val.cos(),
+            ComplexFunction::Tan => val.tan(),
+            ComplexFunction::Sinh => val.sinh(),
+            ComplexFunction::Cosh => val.cosh(),
+            ComplexFunction::Tanh => val.tanh(),
+            ComplexFunction::Asin => val.asin(),
+            ComplexFunction::Atan => val.atan(),
+        }
+    }
+}
+
+impl From<FieldFunction> for ComplexFunction {
+    fn from(fun: FieldFunction) -> Self {
+        ComplexFunction::Field(fun)
+    }
+}
+
+impl AsField<FieldFunction> for ComplexFunction {
+    fn as_field(&self) -> Option<&FieldFunction> {
+        match self {
+            ComplexFunction::Field(fun) => Some(fun),
+            _ => None,
         }
     }
 }
 
-pub type ExprComplex = Expr<Complex<f32>, ComplexFunction, FieldOperator>;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComplexOperator {
+    Field(FieldOperator),
+    /// Principal power `exp(w log z)`, by repeated squaring when `w` is an
+    /// integer.
+    Pow,
+    /// Comparisons of the real parts, `1` if true and `0` otherwise. Compare
+    /// `abs(z)` or `Im(z)` for the modulus or imaginary part.
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+}
+
+impl<T> Operator<Complex<T>> for ComplexOperator
+where
+    T: Real,
+{
+    fn apply(&self, a: Complex<T>, b: Complex<T>) -> Complex<T> {
+        match &self {
+            ComplexOperator::Field(op) => op.apply(a, b),
+            ComplexOperator::Pow => match b.as_integer() {
+                Some(n) => a.powi(n),
+                None => a.pow(b),
+            },
+            ComplexOperator::Less => truth(a.re < b.re),
+            ComplexOperator::LessEq => truth(a.re <= b.re),
+            ComplexOperator::Greater => truth(a.re > b.re),
+            ComplexOperator::GreaterEq => truth(a.re >= b.re),
+        }
+    }
+}
+
+fn truth<T: Zero + One>(b: bool) -> Complex<T> {
+    match b {
+        true => Complex::U,
+        false => Complex::O,
+    }
+}
+
+impl From<FieldOperator> for ComplexOperator {
+    fn from(op: FieldOperator) -> Self {
+        ComplexOperator::Field(op)
+    }
+}
+
+impl AsField<FieldOperator> for ComplexOperator {
+    fn as_field(&self) -> Option<&FieldOperator> {
+        match self {
+            ComplexOperator::Field(op) => Some(op),
+            _ => None,
+        }
+    }
+}
+
+pub type ExprComplex = Expr<Complex<f32>, ComplexFunction, ComplexOperator>;
+pub type ExprComplex64 = Expr<Complex<f64>, ComplexFunction, ComplexOperator>;
+
+/// A program of `depth` definitions, `g0(w) = first` and each other one
+/// `step` of the name of the one before, that calls the last one with `arg`.
+/// With the one before called twice, there are `2^depth` paths through the
+/// calls, for the tests that each definition is visited once.
+#[cfg(test)]
+pub(crate) fn call_chain(
+    depth: usize,
+    first: &str,
+    step: impl Fn(&str) -> String,
+    arg: &str,
+) -> String {
+    let mut program = format!("g0(w) = {first};");
+    for k in 1..depth {
+        program.push_str(&format!(" g{k}(w) = {};", step(&format!("g{}", k - 1))));
+    }
+    program + &format!(" g{}({arg})", depth - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{EvalError, Expr, ExprComplex, Variable};
+    use crate::algebra::Complex;
+
+    #[test]
+    fn free_variables() {
+        let expr = "lambda*z^2+c+t_0*z".parse::<ExprComplex>().unwrap();
+        let names: Vec<_> = expr.free_variables().iter().map(|v| v.name()).collect();
+        assert_eq!(names, ["c", "lambda", "t_0", "z"]);
+        let expr = "2*pi*i".parse::<ExprComplex>().unwrap();
+        assert!(expr.free_variables().is_empty());
+    }
+
+    #[test]
+    fn eval_by_name() {
+        let expr = "a*z+b".parse::<ExprComplex>().unwrap();
+        let mut values = HashMap::new();
+        values.insert("a", Complex::from(2.0));
+        values.insert("b", Complex::I);
+        values.insert("z", Complex::from(3.0));
+        assert_eq!(expr.eval(&values), Complex { re: 6.0, im: 1.0 });
+        let mut values = HashMap::new();
+        values.insert(Variable::new("a"), Complex::from(2.0));
+        values.insert(Variable::new("b"), Complex::I);
+        values.insert(Variable::Z, Complex::from(3.0));
+        assert_eq!(expr.eval(&values), Complex { re: 6.0, im: 1.0 });
+    }
+
+    #[test]
+    fn try_eval() {
+        let mut values = HashMap::new();
+        values.insert("z", Complex::from(0.0));
+        let try_eval = |input: &str| input.parse::<ExprComplex>().unwrap().try_eval(&values);
+        assert_eq!(try_eval("2*z+1"), Ok(Complex::from(1.0)));
+        assert_eq!(
+            try_eval("z+c"),
+            Err(EvalError::UnboundVariable(Variable::C))
+        );
+        assert_eq!(
+            try_eval("exp(1/z)+1"),
+            Err(EvalError::NonFinite("1/z".parse().unwrap()))
+        );
+        assert_eq!(
+            try_eval("log(z)*0").unwrap_err().to_string(),
+            "'log(z)' is not finite"
+        );
+        assert_eq!(
+            try_eval("z/z").unwrap_err().to_string(),
+            "'z/z' is not finite"
+        );
+        // a variable with an infinite value is itself the culprit
+        values.insert("z", Complex::from(f32::INFINITY));
+        let err = "2*z".parse::<ExprComplex>().unwrap().try_eval(&values);
+        assert_eq!(err, Err(EvalError::NonFinite(Expr::Variable(Variable::Z))));
+    }
+
+    #[test]
+    #[should_panic(expected = "no value for variable 'b'")]
+    fn eval_unbound() {
+        let expr = "z+b".parse::<ExprComplex>().unwrap();
+        let mut values = HashMap::new();
+        values.insert("z", Complex::from(3.0));
+        expr.eval(&values);
+    }
+
+    #[test]
+    fn free_variables_bound() {
+        let expr = "g(w) = w*a+z; let t = c in g(t*b)"
+            .parse::<ExprComplex>()
+            .unwrap();
+        let names: Vec<_> = expr.free_variables().iter().map(|v| v.name()).collect();
+        assert_eq!(names, ["a", "b", "c", "z"]);
+    }
+}
@@ -1,5 +1,20 @@
-mod algebra;
-mod expression;
+//! Parsing, evaluation and drawing of complex functions. The `webgl`
+//! feature adds the WebGL plotter and its JS bindings.
+
+pub mod algebra;
+pub mod bytecode;
+pub mod degree;
+pub mod derivative;
+#[cfg(feature = "png")]
+pub mod export;
+pub mod expression;
+#[cfg(feature = "webgl")]
 mod log;
-mod parser;
-mod plotter;
\ No newline at end of file
+pub mod palette;
+pub mod parser;
+#[cfg(feature = "webgl")]
+pub mod plotter;
+pub mod printer;
+pub mod render;
+pub mod scene;
+pub mod simplify;
@@ -1,5 +1,7 @@
+#[allow(unused_macros)]
 macro_rules! info {
     ($($t:tt)*) => (web_sys::console::log_1(&format_args!($($t)*).to_string().into()))
 }
 
+#[allow(unused_imports)]
 pub(crate) use info;
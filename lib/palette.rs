@@ -0,0 +1,323 @@
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "webgl")]
+use wasm_bindgen::prelude::*;
+
+/// Most stops a palette can have, the size of the `palette` uniform array in
+/// `plotter.frag`.
+pub const MAX_STOPS: usize = 16;
+
+/// How escape times map to the gradient, see [`Palette::color`].
+#[cfg_attr(feature = "webgl", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaletteScale {
+    /// Proportional to the number of iterations.
+    #[default]
+    Linear = 1,
+    /// Proportional to its logarithm, which gives more colours to the orbits
+    /// that escape fast, far from the set.
+    Log = 2,
+}
+
+/// A colour of the gradient, from `0` for the fastest escapes to `1` for the
+/// slowest.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Stop {
+    pub position: f32,
+    /// Red, green and blue in `[0, 1]`.
+    pub color: [f32; 3],
+}
+
+/// The colours of the escape-time modes: a gradient between stops and how
+/// escape times map to it. Points that do not escape stay black.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Unchecked")]
+pub struct Palette {
+    stops: Vec<Stop>,
+    pub scale: PaletteScale,
+    /// Repeats the gradient instead of stopping at its ends.
+    pub cyclic: bool,
+    /// Shifts the gradient, in gradient lengths.
+    pub offset: f32,
+    /// Number of gradient lengths over the range of escape times.
+    pub density: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteError {
+    UnknownName(String),
+    /// Fewer than 1 or more than [`MAX_STOPS`].
+    StopCount(usize),
+    /// Positions must be in `[0, 1]` in increasing order, colours in
+    /// `[0, 1]`.
+    InvalidStop(Stop),
+}
+
+impl std::fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaletteError::UnknownName(name) => write!(
+                f,
+                "unknown palette '{name}', expected one of {}",
+                Palette::NAMES.join(", ")
+            ),
+            PaletteError::StopCount(count) => {
+                write!(f, "{count} palette stops, expected 1 to {MAX_STOPS}")
+            }
+            PaletteError::InvalidStop(Stop { position, color }) => write!(
+                f,
+                "invalid palette stop {color:?} at {position}, positions must increase \
+                 in [0, 1] and colours be in [0, 1]"
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "webgl")]
+impl From<PaletteError> for JsValue {
+    fn from(err: PaletteError) -> Self {
+        err.to_string().into()
+    }
+}
+
+/// A [`Palette`] as read, before the stops are checked.
+#[derive(Deserialize)]
+struct Unchecked {
+    stops: Vec<Stop>,
+    scale: PaletteScale,
+    cyclic: bool,
+    offset: f32,
+    density: f32,
+}
+
+impl TryFrom<Unchecked> for Palette {
+    type Error = PaletteError;
+
+    fn try_from(palette: Unchecked) -> Result<Self, Self::Error> {
+        Ok(Palette {
+            scale: palette.scale,
+            cyclic: palette.cyclic,
+            offset: palette.offset,
+            density: palette.density,
+            ..Palette::new(palette.stops)?
+        })
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::named("magenta").unwrap()
+    }
+}
+
+fn check_stops(stops: &[Stop]) -> Result<(), PaletteError> {
+    if stops.is_empty() || stops.len() > MAX_STOPS {
+        return Err(PaletteError::StopCount(stops.len()));
+    }
+    let mut last = 0.0;
+    for &stop in stops {
+        let in_range = |x: f32| (0.0..=1.0).contains(&x);
+        let valid = in_range(stop.position) && stop.position >= last;
+        if !valid || !stop.color.into_iter().all(in_range) {
+            return Err(PaletteError::InvalidStop(stop));
+        }
+        last = stop.position;
+    }
+    Ok(())
+}
+
+fn rgb(hex: u32) -> [f32; 3] {
+    let channel = |shift: u32| ((hex >> shift) & 0xff) as f32 / 255.0;
+    [channel(16), channel(8), channel(0)]
+}
+
+impl Palette {
+    /// The built-in gradients. `viridis` and `cividis` stay readable with
+    /// colour vision deficiencies, their lightness increasing steadily.
+    pub const NAMES: &'static [&'static str] = &[
+        "magenta",
+        "grayscale",
+        "fire",
+        "ocean",
+        "rainbow",
+        "viridis",
+        "cividis",
+    ];
+
+    /// A linear gradient through `stops`, in order of position.
+    pub fn new(stops: Vec<Stop>) -> Result<Palette, PaletteError> {
+        check_stops(&stops)?;
+        Ok(Palette {
+            stops,
+            scale: PaletteScale::Linear,
+            cyclic: false,
+            offset: 0.0,
+            density: 1.0,
+        })
+    }
+
+    /// One of the gradients in [`Palette::NAMES`], evenly spaced.
+    pub fn named(name: &str) -> Result<Palette, PaletteError> {
+        let colors = match name {
+            "magenta" => [0x000000, 0xff00ff].as_slice(),
+            "grayscale" => &[0x000000, 0xffffff],
+            "fire" => &[0x000000, 0xb00000, 0xff8000, 0xffff80],
+            "ocean" => &[0x000010, 0x004080, 0x00a0c0, 0xe0ffff],
+            "rainbow" => &[0xff0000, 0xffff00, 0x00ff00, 0x00ffff, 0x0000ff, 0xff00ff],
+            "viridis" => &[0x440154, 0x3b528b, 0x21918c, 0x5ec962, 0xfde725],
+            "cividis" => &[0x00204d, 0x414d6b, 0x7c7b78, 0xbcaf6f, 0xffea46],
+            _ => return Err(PaletteError::UnknownName(name.to_string())),
+        };
+        let last = (colors.len() - 1) as f32;
+        let stops = (colors.iter().enumerate())
+            .map(|(k, &hex)| Stop {
+                position: k as f32 / last,
+                color: rgb(hex),
+            })
+            .collect();
+        Palette::new(stops)
+    }
+
+    pub fn stops(&self) -> &[Stop] {
+        &self.stops
+    }
+
+    /// Replaces the gradient, keeping the mapping.
+    pub fn set_stops(&mut self, stops: Vec<Stop>) -> Result<(), PaletteError> {
+        check_stops(&stops)?;
+        self.stops = stops;
+        Ok(())
+    }
+
+    /// The colour of an orbit that escaped after `n` of `max_iter`
+    /// iterations, `n` being smoothed, as `palette_color` in `plotter.frag`.
+    /// `max_iter` must be at least 1, see [`crate::render::check_max_iter`].
+    pub fn color(&self, n: f32, max_iter: i32) -> [f32; 3] {
+        let max_iter = max_iter as f32;
+        let n = n.clamp(0.0, max_iter);
+        let t = match self.scale {
+            PaletteScale::Linear => n / max_iter,
+            PaletteScale::Log => n.ln_1p() / max_iter.ln_1p(),
+        };
+        let t = self.offset + self.density * t;
+        // GLSL's `fract`, positive for negative `t`
+        let t = match self.cyclic {
+            true => t - t.floor(),
+            false => t.clamp(0.0, 1.0),
+        };
+        let mut prev = self.stops[0];
+        if t <= prev.position {
+            return prev.color;
+        }
+        for &next in &self.stops[1..] {
+            if t <= next.position {
+                let s = (t - prev.position) / (next.position - prev.position);
+                return [0, 1, 2].map(|k| prev.color[k] + s * (next.color[k] - prev.color[k]));
+            }
+            prev = next;
+        }
+        prev.color
+    }
+
+    /// The stops as the `palette` uniform: red, green, blue and position of
+    /// each.
+    pub fn uniform(&self) -> Vec<f32> {
+        (self.stops.iter())
+            .flat_map(|stop| [stop.color[0], stop.color[1], stop.color[2], stop.position])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Palette, PaletteError, PaletteScale, Stop, MAX_STOPS};
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool {
+        a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-6)
+    }
+
+    #[test]
+    fn gradients() {
+        // the former fixed ramp
+        let palette = Palette::default();
+        for n in [0.0, 10.0, 25.0, 40.0] {
+            let stab = n / 40.0;
+            assert!(close(palette.color(n, 40), [stab, 0.0, stab]));
+        }
+        assert!(close(palette.color(-3.0, 40), [0.0; 3]));
+        assert!(close(palette.color(50.0, 40), [1.0, 0.0, 1.0]));
+        for name in Palette::NAMES {
+            let palette = Palette::named(name).unwrap();
+            assert_eq!(palette.stops()[0].position, 0.0);
+            assert_eq!(palette.stops().last().unwrap().position, 1.0);
+        }
+        let fire = Palette::named("fire").unwrap();
+        assert!(close(fire.color(20.0, 60), [176.0 / 255.0, 0.0, 0.0]));
+        assert!(close(fire.color(10.0, 60), [88.0 / 255.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn mapping() {
+        let mut palette = Palette::named("grayscale").unwrap();
+        palette.scale = PaletteScale::Log;
+        let gray = palette.color(15.0, 255)[0];
+        assert!((gray - 0.5).abs() < 1e-6, "{gray}");
+        palette.scale = PaletteScale::Linear;
+        palette.offset = 0.25;
+        assert!(close(palette.color(50.0, 100), [0.75; 3]));
+        assert!(close(palette.color(100.0, 100), [1.0; 3]));
+        palette.cyclic = true;
+        assert!(close(palette.color(100.0, 100), [0.25; 3]));
+        palette.density = 3.0;
+        palette.offset = -0.1;
+        assert!(close(palette.color(50.0, 100), [0.4; 3]));
+        assert!(close(palette.color(0.0, 100), [0.9; 3]));
+    }
+
+    #[test]
+    fn stops() {
+        let stop = |position, gray| Stop {
+            position,
+            color: [gray; 3],
+        };
+        let palette = Palette::new(vec![stop(0.25, 0.0), stop(0.75, 1.0)]).unwrap();
+        assert!(close(palette.color(10.0, 100), [0.0; 3]));
+        assert!(close(palette.color(50.0, 100), [0.5; 3]));
+        assert!(close(palette.color(90.0, 100), [1.0; 3]));
+        assert_eq!(
+            palette.uniform(),
+            [0.0, 0.0, 0.0, 0.25, 1.0, 1.0, 1.0, 0.75]
+        );
+        // a hard edge
+        let palette = Palette::new(vec![stop(0.5, 0.0), stop(0.5, 1.0)]).unwrap();
+        assert!(close(palette.color(50.0, 100), [0.0; 3]));
+        assert!(close(palette.color(51.0, 100), [1.0; 3]));
+
+        assert_eq!(Palette::new(vec![]), Err(PaletteError::StopCount(0)));
+        let many = vec![stop(0.0, 0.0); MAX_STOPS + 1];
+        assert_eq!(Palette::new(many), Err(PaletteError::StopCount(17)));
+        let unordered = vec![stop(0.5, 0.0), stop(0.25, 1.0)];
+        assert_eq!(
+            Palette::new(unordered),
+            Err(PaletteError::InvalidStop(stop(0.25, 1.0)))
+        );
+        assert!(Palette::new(vec![stop(0.0, 2.0)]).is_err());
+        assert!(Palette::new(vec![stop(f32::NAN, 0.0)]).is_err());
+        assert_eq!(
+            Palette::named("jet").unwrap_err().to_string(),
+            "unknown palette 'jet', expected one of magenta, grayscale, fire, ocean, \
+             rainbow, viridis, cividis"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let mut palette = Palette::named("viridis").unwrap();
+        palette.cyclic = true;
+        let json = serde_json::to_string(&palette).unwrap();
+        assert_eq!(serde_json::from_str::<Palette>(&json).unwrap(), palette);
+        let json = r#"{"stops":[],"scale":"log","cyclic":false,"offset":0,"density":1}"#;
+        let err = serde_json::from_str::<Palette>(json).unwrap_err();
+        assert_eq!(err.to_string(), "0 palette stops, expected 1 to 16");
+    }
+}
@@ -1,29 +1,206 @@
-use std::str::FromStr;
+use std::{ops::Range, rc::Rc, str::FromStr};
 
 use crate::{
-    algebra::{Complex, Ring},
-    expression::{ComplexFunction, Expr, FieldFunction, FieldOperator, Variable},
+    algebra::{Complex, Dual, Ring},
+    expression::{
+        ComplexFunction, ComplexOperator, Definition, Expr, FieldFunction, FieldOperator, Variable,
+    },
 };
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{char, one_of},
-    combinator::{map, peek, value},
-    error::Error,
-    multi::fold_many0,
-    number::complete::float,
-    sequence::{delimited, pair, preceded, tuple},
+    bytes::complete::{tag, take_while},
+    character::complete::{char, digit0, digit1, one_of, satisfy},
+    combinator::{cut, map, map_opt, not, opt, peek, recognize, value, verify},
+    error::ErrorKind,
+    multi::{fold_many0, separated_list0},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     Finish, IResult,
 };
 
-trait Parseable: Clone {
-    fn parse(i: &str) -> IResult<&str, Self>
+/// What the parser was looking for where it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    /// A number, variable, function or parenthesised expression.
+    Operand,
+    OpeningParen,
+    ClosingParen,
+    Comma,
+    Operator,
+    EndOfInput,
+    /// A variable or definition name.
+    Name,
+    Equals,
+    In,
+    Semicolon,
+    /// Anything but a call of the definition whose body it is.
+    NonRecursive,
+}
+
+impl std::fmt::Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Expected::Operand => "a number, variable, function or '('",
+            Expected::OpeningParen => "'('",
+            Expected::ClosingParen => "')'",
+            Expected::Comma => "','",
+            Expected::Operator => "an operator",
+            Expected::EndOfInput => "end of input",
+            Expected::Name => "a name",
+            Expected::Equals => "'='",
+            Expected::In => "'in'",
+            Expected::Semicolon => "';'",
+            Expected::NonRecursive => "a call of an earlier definition, not a recursive one",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub input: String,
+    /// Byte offset where parsing failed.
+    pub offset: usize,
+    /// Byte range of the offending token, empty at the end of the input.
+    pub span: Range<usize>,
+    pub expected: Vec<Expected>,
+}
+
+impl ParseError {
+    fn new(input: &str, offset: usize, expected: Vec<Expected>) -> Self {
+        let rest = &input[offset..];
+        let token = match rest.chars().next() {
+            Some(c) if c.is_alphanumeric() => rest
+                .find(|c: char| !c.is_alphanumeric())
+                .unwrap_or(rest.len()),
+            Some(c) => c.len_utf8(),
+            None => 0,
+        };
+        ParseError {
+            input: input.to_string(),
+            offset,
+            span: offset..offset + token,
+            expected,
+        }
+    }
+}
+
+/// Renders as
+/// ```text
+/// expected ')' at 4
+/// (z+1
+///     ^
+/// ```
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.expected.split_last() {
+            None => write!(f, "unexpected input")?,
+            Some((last, [])) => write!(f, "expected {last}")?,
+            Some((last, init)) => {
+                write!(f, "expected ")?;
+                for expected in init {
+                    write!(f, "{expected}, ")?;
+                }
+                write!(f, "or {last}")?;
+            }
+        }
+        writeln!(f, " at {}", self.offset)?;
+        writeln!(f, "{}", self.input)?;
+        let column = self.input[..self.span.start].chars().count();
+        let width = self.input[self.span.clone()].chars().count().max(1);
+        write!(f, "{}{}", " ".repeat(column), "^".repeat(width))
+    }
+}
+
+/// nom error that keeps the failure that got furthest into the input, with
+/// everything that was expected there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure<'a> {
+    input: &'a str,
+    expected: Vec<Expected>,
+}
+
+impl<'a> nom::error::ParseError<&'a str> for Failure<'a> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        Failure {
+            input,
+            expected: vec![],
+        }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(mut self, other: Self) -> Self {
+        match self.input.len().cmp(&other.input.len()) {
+            std::cmp::Ordering::Less => self,
+            std::cmp::Ordering::Greater => other,
+            std::cmp::Ordering::Equal => {
+                for expected in other.expected {
+                    if !self.expected.contains(&expected) {
+                        self.expected.push(expected);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+pub type PResult<'a, O> = IResult<&'a str, O, Failure<'a>>;
+
+/// Reports `expected` when `parser` fails without consuming any input,
+/// unless it failed for good knowing what it expected.
+fn expect<'a, O>(
+    expected: Expected,
+    mut parser: impl FnMut(&'a str) -> PResult<'a, O>,
+) -> impl FnMut(&'a str) -> PResult<'a, O> {
+    move |i| {
+        parser(i).map_err(|err| match err {
+            nom::Err::Failure(failure) if !failure.expected.is_empty() => {
+                nom::Err::Failure(failure)
+            }
+            err => err.map(|failure| match failure.input.len() == i.len() {
+                true => Failure {
+                    input: i,
+                    expected: vec![expected],
+                },
+                false => failure,
+            }),
+        })
+    }
+}
+
+/// Skips any Unicode whitespace before `parser`, including the non-breaking
+/// spaces that a contenteditable element inserts.
+fn ws<'a, O>(
+    parser: impl FnMut(&'a str) -> PResult<'a, O>,
+) -> impl FnMut(&'a str) -> PResult<'a, O> {
+    preceded(take_while(char::is_whitespace), parser)
+}
+
+/// Types read by the expression grammar: constants, functions and
+/// operators.
+pub trait Parseable: Clone {
+    fn parse(i: &str) -> PResult<'_, Self>
     where
         Self: Sized;
+
+    /// `-self` for constants, so that `-2` is read as a single constant
+    /// rather than a negation.
+    fn negate(&self) -> Option<Self> {
+        None
+    }
+
+    /// The constant `re+im`, or `re-im` if `negative`, for constants with a
+    /// real part written `re`, possibly negative, and an imaginary part
+    /// written `im`, so that `1.5-2i` is read as a single constant.
+    fn join(_re: &str, _im: &str, _negative: bool) -> Option<Self> {
+        None
+    }
 }
 
 impl Parseable for FieldOperator {
-    fn parse(i: &str) -> IResult<&str, Self>
+    fn parse(i: &str) -> PResult<'_, Self>
     where
         Self: Sized,
     {
@@ -37,7 +214,7 @@ impl Parseable for FieldOperator {
 }
 
 impl Parseable for FieldFunction {
-    fn parse(i: &str) -> IResult<&str, Self>
+    fn parse(i: &str) -> PResult<'_, Self>
     where
         Self: Sized,
     {
@@ -49,108 +226,528 @@ impl Parseable for FieldFunction {
 }
 
 impl Parseable for ComplexFunction {
-    fn parse(i: &str) -> IResult<&str, Self>
+    fn parse(i: &str) -> PResult<'_, Self>
     where
         Self: Sized,
     {
+        // longer names first, so that `sinh` is not read as `sin` followed by `h`
         alt((
+            map(FieldFunction::parse, ComplexFunction::Field),
             value(ComplexFunction::Re, tag("Re")),
             value(ComplexFunction::Im, tag("Im")),
             value(ComplexFunction::Abs, tag("abs")),
+            value(ComplexFunction::Exp, tag("exp")),
+            value(ComplexFunction::Log, tag("log")),
+            value(ComplexFunction::Sqrt, tag("sqrt")),
+            value(ComplexFunction::Sinh, tag("sinh")),
+            value(ComplexFunction::Cosh, tag("cosh")),
+            value(ComplexFunction::Tanh, tag("tanh")),
+            value(ComplexFunction::Asin, tag("asin")),
+            value(ComplexFunction::Atan, tag("atan")),
+            value(ComplexFunction::Sin, tag("sin")),
+            value(ComplexFunction::Cos, tag("cos")),
+            value(ComplexFunction::Tan, tag("tan")),
         ))(i)
     }
 }
 
-impl Parseable for f32 {
-    fn parse(i: &str) -> IResult<&str, Self>
+impl Parseable for ComplexOperator {
+    fn parse(i: &str) -> PResult<'_, Self>
     where
         Self: Sized,
     {
-        float(i)
+        alt((
+            map(FieldOperator::parse, ComplexOperator::Field),
+            value(ComplexOperator::Pow, char('^')),
+            value(ComplexOperator::Pow, tag("pow")),
+            value(ComplexOperator::LessEq, tag("<=")),
+            value(ComplexOperator::GreaterEq, tag(">=")),
+            value(ComplexOperator::Less, char('<')),
+            value(ComplexOperator::Greater, char('>')),
+        ))(i)
     }
 }
 
-impl<T> Parseable for Complex<T>
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Runs `parser` only on a whole word, so that `pi` does not match the start
+/// of `pix` nor `sin` the start of `sinz`.
+fn word<'a, O>(
+    mut parser: impl FnMut(&'a str) -> PResult<'a, O>,
+) -> impl FnMut(&'a str) -> PResult<'a, O> {
+    move |i| {
+        let (rest, out) = parser(i)?;
+        match rest.starts_with(is_word_char) {
+            true => Err(nom::Err::Error(Failure {
+                input: i,
+                expected: vec![],
+            })),
+            false => Ok((rest, out)),
+        }
+    }
+}
+
+fn keyword<'a>(name: &'static str) -> impl FnMut(&'a str) -> PResult<'a, &'a str> {
+    word(tag(name))
+}
+
+/// Unsigned decimal literal with an optional exponent: `2`, `2.`, `.5`,
+/// `2.5e-3`. The exponent needs digits, so `2e` is `2` times the constant
+/// `e` and `2e-3` is `0.002` rather than `2e - 3`.
+fn number(i: &str) -> PResult<'_, &str> {
+    recognize(pair(
+        alt((
+            recognize(pair(digit1, opt(pair(char('.'), digit0)))),
+            recognize(pair(char('.'), digit1)),
+        )),
+        opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+    ))(i)
+}
+
+macro_rules! impl_parseable_real {
+    ($t:ident) => {
+        /// A number or one of the constants `pi`, `e`, `tau`, `inf` and
+        /// `nan`.
+        impl Parseable for $t {
+            fn parse(i: &str) -> PResult<'_, Self>
+            where
+                Self: Sized,
+            {
+                alt((
+                    map_opt(number, |s| s.parse().ok()),
+                    value(std::$t::consts::PI, keyword("pi")),
+                    value(std::$t::consts::E, keyword("e")),
+                    value(std::$t::consts::TAU, keyword("tau")),
+                    value($t::INFINITY, keyword("inf")),
+                    value($t::NAN, keyword("nan")),
+                ))(i)
+            }
+
+            fn negate(&self) -> Option<Self> {
+                Some(-self)
+            }
+        }
+    };
+}
+
+impl_parseable_real!(f32);
+impl_parseable_real!(f64);
+
+/// Literals are constants, their derivative is zero.
+impl<T> Parseable for Dual<T>
 where
     T: Parseable + Ring,
 {
-    fn parse(i: &str) -> IResult<&str, Self>
+    fn parse(i: &str) -> PResult<'_, Self>
     where
         Self: Sized,
     {
-        alt((map(T::parse, Complex::from), value(Complex::I, char('i'))))(i)
+        map(T::parse, Dual::from)(i)
+    }
+
+    fn negate(&self) -> Option<Self> {
+        Some(-*self)
     }
 }
 
-fn var<T, F, O>(i: &str) -> IResult<&str, Expr<T, F, O>>
+/// The imaginary part of an imaginary literal: `2i`, `i`, or `infi` and
+/// `nani`, which are single words.
+fn imaginary<T>(i: &str) -> PResult<'_, T>
 where
-    T: Clone,
-    F: Clone,
-    O: Clone,
+    T: Parseable + Ring,
 {
+    // `2i` but not `2inv(z)`, which is `2*inv(z)`
     alt((
-        value(Expr::Variable(Variable::Z), char('z')),
-        value(Expr::Variable(Variable::C), char('c')),
+        terminated(T::parse, keyword("i")),
+        map_opt(
+            alt((keyword("infi"), keyword("nani"))),
+            |word: &str| match T::parse(&word[..3]) {
+                Ok(("", im)) => Some(im),
+                _ => None,
+            },
+        ),
+        value(T::U, keyword("i")),
     ))(i)
 }
 
-fn term<T, F, O>(i: &str) -> IResult<&str, Expr<T, F, O>>
+/// What `parser` reads from the whole of `s`, but for whitespace.
+fn whole<'a, T>(s: &'a str, parser: impl FnMut(&'a str) -> PResult<'a, T>) -> Option<T> {
+    let (rest, out) = ws(parser)(s).ok()?;
+    rest.chars().all(char::is_whitespace).then_some(out)
+}
+
+impl<T> Parseable for Complex<T>
+where
+    T: Parseable + Ring,
+{
+    fn parse(i: &str) -> PResult<'_, Self>
+    where
+        Self: Sized,
+    {
+        alt((
+            map(imaginary, |im| Complex { re: T::O, im }),
+            map(T::parse, Complex::from),
+        ))(i)
+    }
+
+    fn negate(&self) -> Option<Self> {
+        Some(-*self)
+    }
+
+    fn join(re: &str, im: &str, negative: bool) -> Option<Self> {
+        let re = match re.trim_start().strip_prefix('-') {
+            Some(re) => -whole(re, T::parse)?,
+            None => whole(re, T::parse)?,
+        };
+        let im = whole(im, imaginary::<T>)?;
+        Some(Complex {
+            re,
+            im: if negative { -im } else { im },
+        })
+    }
+}
+
+/// Definitions visible to an expression, later ones shadow earlier ones.
+struct Definitions<T, F, O> {
+    visible: Vec<Rc<Definition<T, F, O>>>,
+    /// The definition whose body is being parsed, which it cannot call.
+    defining: Option<Variable>,
+}
+
+/// Any identifier that does not name a function, operator or constant, nor is
+/// a keyword, e.g. `z`, `lambda` or `t_0`.
+fn name<T, F, O>(i: &str) -> PResult<'_, Variable>
 where
     T: Parseable,
     F: Parseable,
     O: Parseable,
 {
+    let identifier = recognize(pair(satisfy(char::is_alphabetic), take_while(is_word_char)));
+    let reserved = |name: &str| {
+        matches!(name, "let" | "in" | "if")
+            || matches!(T::parse(name), Ok(("", _)))
+            || matches!(F::parse(name), Ok(("", _)))
+            || matches!(O::parse(name), Ok(("", _)))
+    };
+    map(
+        verify(identifier, move |name: &str| !reserved(name)),
+        Variable::new,
+    )(i)
+}
+
+/// A call `g(a, b)` of a definition, with as many arguments as it has
+/// parameters.
+fn call<'a, T, F, O>(defs: &Definitions<T, F, O>, i: &'a str) -> PResult<'a, Expr<T, F, O>>
+where
+    T: Parseable,
+    F: Parseable,
+    O: Parseable + From<FieldOperator>,
+{
+    let (rest, callee) = name::<T, F, O>(i)?;
+    let Some(def) = defs
+        .visible
+        .iter()
+        .rev()
+        .find(|def| def.name == callee.name())
+    else {
+        // a redefinition may call the definition it shadows, found above
+        let recursive = defs.defining.as_ref() == Some(&callee);
+        return Err(
+            match recursive && ws(char::<_, Failure>('('))(rest).is_ok() {
+                true => nom::Err::Failure(Failure {
+                    input: i,
+                    expected: vec![Expected::NonRecursive],
+                }),
+                false => nom::Err::Error(Failure {
+                    input: i,
+                    expected: vec![],
+                }),
+            },
+        );
+    };
+    // a defined name is not a variable, it must be called
+    let (mut i, _) = cut(ws(expect(Expected::OpeningParen, char('('))))(rest)?;
+    let mut args = vec![];
+    for k in 0..def.params.len() {
+        if k > 0 {
+            (i, _) = cut(ws(expect(Expected::Comma, char(','))))(i)?;
+        }
+        let (rest, arg) = cut(|i| expr(defs, i))(i)?;
+        args.push(arg);
+        i = rest;
+    }
+    let (i, _) = cut(ws(expect(Expected::ClosingParen, char(')'))))(i)?;
+    Ok((i, Expr::Call(def.clone(), args)))
+}
+
+fn term<'a, T, F, O>(defs: &Definitions<T, F, O>, i: &'a str) -> PResult<'a, Expr<T, F, O>>
+where
+    T: Parseable,
+    F: Parseable,
+    O: Parseable + From<FieldOperator>,
+{
+    // named operators are written in call syntax, e.g. `pow(z, 2)`
+    let call_op = word(preceded(peek(satisfy(char::is_alphabetic)), O::parse));
+    let args = delimited(
+        ws(expect(Expected::OpeningParen, char('('))),
+        separated_pair(
+            |i| expr(defs, i),
+            ws(expect(Expected::Comma, char(','))),
+            |i| expr(defs, i),
+        ),
+        ws(expect(Expected::ClosingParen, char(')'))),
+    );
+    let parens = delimited(
+        char('('),
+        |i| expr(defs, i),
+        ws(expect(Expected::ClosingParen, char(')'))),
+    );
+    let if_else = preceded(
+        keyword("if"),
+        cut(delimited(
+            ws(expect(Expected::OpeningParen, char('('))),
+            tuple((
+                |i| expr(defs, i),
+                preceded(ws(expect(Expected::Comma, char(','))), |i| expr(defs, i)),
+                preceded(ws(expect(Expected::Comma, char(','))), |i| expr(defs, i)),
+            )),
+            ws(expect(Expected::ClosingParen, char(')'))),
+        )),
+    );
+    // symbolic functions, i.e. negation, are parsed by `unary`
+    let named_fun = word(preceded(peek(satisfy(char::is_alphabetic)), F::parse));
+    // functions go before constants and variables, which may be their prefix,
+    // e.g. `e` and `exp`
+    ws(expect(
+        Expected::Operand,
+        alt((
+            parens,
+            map(if_else, |(cond, then, otherwise)| {
+                Expr::if_else(cond, then, otherwise)
+            }),
+            map(pair(call_op, args), |(op, (left, right))| {
+                Expr::Operator(op, Box::new(left), Box::new(right))
+            }),
+            map(pair(named_fun, |i| term(defs, i)), |(fun, t)| {
+                Expr::Function(fun, Box::new(t))
+            }),
+            |i| call(defs, i),
+            map(T::parse, |ct: T| Expr::Constant(ct)),
+            map(name::<T, F, O>, Expr::Variable),
+        )),
+    ))(i)
+}
+
+/// `term ^ unary`, right associative as the exponent may itself be a power.
+fn power<'a, T, F, O>(defs: &Definitions<T, F, O>, i: &'a str) -> PResult<'a, Expr<T, F, O>>
+where
+    T: Parseable,
+    F: Parseable,
+    O: Parseable + From<FieldOperator>,
+{
+    let (i, base) = term(defs, i)?;
+    let pow_op = ws(preceded(peek(char('^')), O::parse));
+    let (i, exponent) = opt(pair(pow_op, cut(|i| unary(defs, i))))(i)?;
+    Ok(match exponent {
+        Some((op, exponent)) => (i, Expr::Operator(op, Box::new(base), Box::new(exponent))),
+        None => (i, base),
+    })
+}
+
+/// Prefix negation binds looser than `^`, so `-z^2` is `-(z^2)`. A negated
+/// literal that is not a base is a single constant, see
+/// [`Parseable::negate`].
+fn unary<'a, T, F, O>(defs: &Definitions<T, F, O>, i: &'a str) -> PResult<'a, Expr<T, F, O>>
+where
+    T: Parseable,
+    F: Parseable,
+    O: Parseable + From<FieldOperator>,
+{
+    let neg = ws(preceded(peek(char('-')), F::parse));
+    let literal = terminated(ws(T::parse), not(ws(char('^'))));
+    let negative_literal = map_opt(preceded(ws(char('-')), literal), |ct| ct.negate());
     alt((
-        delimited(tag("("), add, tag(")")),
-        var,
-        map(T::parse, |ct: T| Expr::Constant(ct)),
-        map(pair(F::parse, term), |(fun, t)| {
-            Expr::Function(fun, Box::new(t))
+        map(negative_literal, Expr::Constant),
+        map(pair(neg, |i| unary(defs, i)), |(fun, e)| {
+            Expr::Function(fun, Box::new(e))
         }),
+        |i| power(defs, i),
     ))(i)
 }
 
-fn mul<T, F, O>(i: &str) -> IResult<&str, Expr<T, F, O>>
+/// Products and quotients, left associative. Adjacent operands multiply,
+/// `2z`, `2 sin(z)` or `(z-1)(z+1)`, at the same precedence as `*`, so `1/2z`
+/// is `(1/2)*z` and `2z^2` is `2*(z^2)`. An implicit factor cannot start with
+/// a digit, as `2 3` is more likely a typo than a product, nor with a sign,
+/// which is read as an operator. Identifiers are read whole, so `zc` is a
+/// variable and not `z*c`. A prefix function takes a single term, so
+/// `sin 2z` is `sin(2)*z`.
+fn mul<'a, T, F, O>(defs: &Definitions<T, F, O>, i: &'a str) -> PResult<'a, Expr<T, F, O>>
 where
     T: Parseable,
     F: Parseable,
-    O: Parseable,
+    O: Parseable + From<FieldOperator>,
 {
-    let (i, init) = term(i)?;
-    let mul_or_div_op = preceded(peek(one_of("*/")), O::parse);
+    let (i, init) = unary(defs, i)?;
+    let mul_or_div_op = ws(preceded(peek(one_of("*/")), O::parse));
+    let implicit_factor = preceded(ws(not(satisfy(|c| c.is_ascii_digit() || c == '.'))), |i| {
+        power(defs, i)
+    });
     fold_many0(
-        tuple((mul_or_div_op, term)),
+        alt((
+            // an operator must be followed by its operand, no backtracking
+            tuple((mul_or_div_op, cut(|i| unary(defs, i)))),
+            map(implicit_factor, |right| (FieldOperator::Mul.into(), right)),
+        )),
         move || init.clone(),
         |left, (op, right)| Expr::Operator(op, Box::new(left), Box::new(right)),
     )(i)
 }
 
-fn add<T, F, O>(i: &str) -> IResult<&str, Expr<T, F, O>>
+fn add<'a, T, F, O>(defs: &Definitions<T, F, O>, i: &'a str) -> PResult<'a, Expr<T, F, O>>
 where
     T: Parseable,
     F: Parseable,
-    O: Parseable,
+    O: Parseable + From<FieldOperator>,
 {
-    let (i, init) = mul(i)?;
-    let add_or_sub_op = preceded(peek(one_of("+-")), O::parse);
+    let (rest, mut init) = mul(defs, i)?;
+    let left = &i[..i.len() - rest.len()];
+    let mut add_or_sub_op = ws(preceded(
+        peek(one_of("+-")),
+        pair(peek(one_of("+-")), O::parse),
+    ));
+    let mut i = rest;
+    // `1.5-2i` is a single constant, see `Parseable::join`
+    if let Ok((rest, (sign, op))) = add_or_sub_op(i) {
+        let (after, right) = cut(|i| mul(defs, i))(rest)?;
+        init = match T::join(left, &rest[..rest.len() - after.len()], sign == '-') {
+            Some(ct) => Expr::Constant(ct),
+            None => Expr::Operator(op, Box::new(init), Box::new(right)),
+        };
+        i = after;
+    }
     fold_many0(
-        tuple((add_or_sub_op, mul)),
+        tuple((add_or_sub_op, cut(|i| mul(defs, i)))),
         move || init.clone(),
-        |left, (op, right)| Expr::Operator(op, Box::new(left), Box::new(right)),
+        |left, ((_, op), right)| Expr::Operator(op, Box::new(left), Box::new(right)),
     )(i)
 }
 
-impl<T, F, O> Parseable for Expr<T, F, O>
+/// `sum < sum`, or a sum. Comparisons do not chain, `a < b < c` is an error.
+fn comparison<'a, T, F, O>(defs: &Definitions<T, F, O>, i: &'a str) -> PResult<'a, Expr<T, F, O>>
+where
+    T: Parseable,
+    F: Parseable,
+    O: Parseable + From<FieldOperator>,
+{
+    let (i, left) = add(defs, i)?;
+    let compare_op = ws(preceded(peek(one_of("<>")), O::parse));
+    let (i, right) = opt(pair(compare_op, cut(|i| add(defs, i))))(i)?;
+    Ok(match right {
+        Some((op, right)) => (i, Expr::Operator(op, Box::new(left), Box::new(right))),
+        None => (i, left),
+    })
+}
+
+/// `let w = bound in body`, or a comparison. The body extends as far right as
+/// possible, so a `let` inside an operand needs parentheses.
+fn expr<'a, T, F, O>(defs: &Definitions<T, F, O>, i: &'a str) -> PResult<'a, Expr<T, F, O>>
+where
+    T: Parseable,
+    F: Parseable,
+    O: Parseable + From<FieldOperator>,
+{
+    let let_in = preceded(
+        ws(keyword("let")),
+        cut(tuple((
+            ws(expect(Expected::Name, name::<T, F, O>)),
+            preceded(ws(expect(Expected::Equals, char('='))), |i| expr(defs, i)),
+            preceded(ws(expect(Expected::In, keyword("in"))), |i| expr(defs, i)),
+        ))),
+    );
+    alt((
+        map(let_in, |(var, bound, body)| Expr::let_in(var, bound, body)),
+        |i| comparison(defs, i),
+    ))(i)
+}
+
+/// `g(w, x) =`, the head of a definition.
+fn definition_head<T, F, O>(i: &str) -> PResult<'_, (Variable, Vec<Variable>)>
 where
     T: Parseable,
     F: Parseable,
     O: Parseable,
 {
-    fn parse(i: &str) -> IResult<&str, Self>
+    let params = separated_list0(ws(char(',')), ws(name::<T, F, O>));
+    terminated(
+        pair(
+            ws(name::<T, F, O>),
+            delimited(ws(char('(')), params, ws(char(')'))),
+        ),
+        ws(char('=')),
+    )(i)
+}
+
+/// A program: definitions `g(w) = body` separated by `;`, then the
+/// expression to plot. Definitions may call earlier ones but not themselves,
+/// as GLSL has no recursion. The last statement may itself be a definition,
+/// `f(z) = body` or `f(z, c) = body`, whose parameters are bound to `z` and
+/// `c` in order.
+impl<T, F, O> Parseable for Expr<T, F, O>
+where
+    T: Parseable,
+    F: Parseable,
+    O: Parseable + From<FieldOperator>,
+{
+    fn parse(mut i: &str) -> PResult<'_, Self>
     where
         Self: Sized,
     {
-        add(i)
+        let mut defs = Definitions {
+            visible: vec![],
+            defining: None,
+        };
+        loop {
+            let Ok((rest, (callee, params))) = definition_head::<T, F, O>(i) else {
+                let (i, e) = expr(&defs, i)?;
+                let (i, _) = opt(ws(char(';')))(i)?;
+                return Ok((take_while(char::is_whitespace)(i)?.0, e));
+            };
+            defs.defining = Some(callee.clone());
+            let (rest, body) = cut(|i| expr(&defs, i))(rest)?;
+            defs.defining = None;
+            let def = Definition {
+                name: callee.name().to_string(),
+                params,
+                body,
+            };
+            let (rest, separator) = opt(ws(char(';')))(rest)?;
+            let (rest, _) = take_while(char::is_whitespace)(rest)?;
+            if separator.is_none() || rest.is_empty() {
+                let globals = [Variable::Z, Variable::C];
+                if def.params.len() > globals.len() {
+                    return Err(nom::Err::Failure(Failure {
+                        input: i,
+                        expected: vec![Expected::Semicolon],
+                    }));
+                }
+                let e = match def.params.iter().eq(&globals[..def.params.len()]) {
+                    true => def.body,
+                    false => {
+                        let args = globals[..def.params.len()]
+                            .iter()
+                            .cloned()
+                            .map(Expr::Variable);
+                        Expr::Call(Rc::new(def), args.collect())
+                    }
+                };
+                return Ok((rest, e));
+            }
+            defs.visible.push(Rc::new(def));
+            i = rest;
+        }
     }
 }
 
@@ -158,13 +755,37 @@ impl<T, F, O> FromStr for Expr<T, F, O>
 where
     T: Parseable,
     F: Parseable,
-    O: Parseable,
+    O: Parseable + From<FieldOperator>,
 {
-    type Err = String;
+    type Err = ParseError;
+    /// Parses the whole of `s`, see [`Expr::parse_prefix`] to allow trailing
+    /// input.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::parse_prefix(s)? {
+            (expr, "") => Ok(expr),
+            (_, rest) => Err(ParseError::new(
+                s,
+                s.len() - rest.len(),
+                vec![Expected::Operator, Expected::EndOfInput],
+            )),
+        }
+    }
+}
+
+impl<T, F, O> Expr<T, F, O>
+where
+    T: Parseable,
+    F: Parseable,
+    O: Parseable + From<FieldOperator>,
+{
+    /// Parses the longest prefix of `s` that is an expression, returning it
+    /// together with the rest of the input.
+    pub fn parse_prefix(s: &str) -> Result<(Self, &str), ParseError> {
         match Self::parse(s).finish() {
-            Ok((_remaining, name)) => Ok(name),
-            Err(Error { input, code }) => Err(format!("Bad parse '{}': {:?}", input, code)),
+            Ok((rest, expr)) => Ok((expr, rest)),
+            Err(Failure { input, expected }) => {
+                Err(ParseError::new(s, s.len() - input.len(), expected))
+            }
         }
     }
 }
@@ -173,15 +794,18 @@ where
 mod test {
     use std::collections::HashMap;
 
-    use super::Parseable;
+    use super::{Expected, ParseError, Parseable};
     use crate::{
-        algebra::{Complex, Field},
-        expression::{Expr, FieldFunction, FieldOperator, Variable},
+        algebra::{Complex, Dual, Field, Transcendental, Zero},
+        expression::{
+            ComplexFunction, ComplexOperator, Expr, ExprComplex, ExprComplex64, FieldFunction,
+            FieldOperator, Variable,
+        },
     };
 
     fn parse_and_eval<T>(input: &str) -> T
     where
-        T: Parseable + Field,
+        T: Parseable + Field + PartialEq,
     {
         let mut values = HashMap::new();
         values.insert(Variable::Z, T::O);
@@ -191,6 +815,20 @@ mod test {
             .eval(&values)
     }
 
+    fn parse_and_eval_complex(input: &str, z: Complex<f32>) -> Complex<f32> {
+        let mut values = HashMap::new();
+        values.insert(Variable::Z, z);
+        input.parse::<ExprComplex>().unwrap().eval(&values)
+    }
+
+    fn assert_close(a: Complex<f32>, b: Complex<f32>) {
+        assert!((a - b).norm_sq() < 1e-10, "{a} != {b}")
+    }
+
+    fn parse_error(input: &str) -> ParseError {
+        input.parse::<ExprComplex>().unwrap_err()
+    }
+
     #[test]
     fn literal() {
         assert_eq!(parse_and_eval::<f32>("1"), 1.0)
@@ -258,4 +896,568 @@ mod test {
             Complex::from(5.0)
         )
     }
+
+    #[test]
+    fn complex_neg() {
+        assert_eq!(parse_and_eval_complex("-z", Complex::I), -Complex::I)
+    }
+
+    #[test]
+    fn transcendental() {
+        assert_close(
+            parse_and_eval_complex("exp(z)", Complex::O),
+            Complex::from(1.0),
+        );
+        assert_close(
+            parse_and_eval_complex("log(z)", Complex::from(1.0)),
+            Complex::O,
+        );
+        assert_close(
+            parse_and_eval_complex("sqrt(z)", Complex::from(-1.0)),
+            Complex::I,
+        );
+        assert_close(
+            parse_and_eval_complex("sin(z)/z", Complex::from(1e-3)),
+            Complex::from(1.0),
+        );
+        assert_close(
+            parse_and_eval_complex("exp(1/z)", Complex::from(1.0)),
+            Complex::from(std::f32::consts::E),
+        );
+    }
+
+    #[test]
+    fn longest_name() {
+        let z = Complex { re: 0.5, im: 0.25 };
+        assert_close(
+            parse_and_eval_complex("sinh(z)", z),
+            parse_and_eval_complex("-i*sin(i*z)", z),
+        );
+        assert_close(
+            parse_and_eval_complex("cosh(z)", z),
+            parse_and_eval_complex("cos(i*z)", z),
+        );
+        assert_close(
+            parse_and_eval_complex("tanh(z)", z),
+            parse_and_eval_complex("-i*tan(i*z)", z),
+        );
+    }
+
+    #[test]
+    fn function_before_variable() {
+        let z = Complex { re: 0.5, im: 0.25 };
+        assert_close(parse_and_eval_complex("Im(z)", z), Complex::from(0.25));
+        assert_close(parse_and_eval_complex("cos(z)", z), z.cos());
+    }
+
+    #[test]
+    fn pow_call() {
+        let z = Complex { re: 0.5, im: 0.25 };
+        assert_close(parse_and_eval_complex("pow(z,2)", z), z * z);
+        assert_close(
+            parse_and_eval_complex("pow(z,z+1)/z", z),
+            parse_and_eval_complex("pow(z,z)", z),
+        );
+    }
+
+    #[test]
+    fn double() {
+        assert_eq!(parse_and_eval::<f64>("0.1+0.2"), 0.1 + 0.2);
+        assert_eq!(
+            parse_and_eval::<Complex<f64>>("(1.000000000001-i)*i"),
+            Complex {
+                re: 1.0,
+                im: 1.000000000001
+            }
+        );
+    }
+
+    #[test]
+    fn complex64() {
+        let mut values = HashMap::new();
+        values.insert(Variable::Z, Complex::from(2.0));
+        let expr = "sqrt(z)*sqrt(z)".parse::<ExprComplex64>().unwrap();
+        assert!((expr.eval(&values) - Complex::from(2.0)).norm_sq() < 1e-30);
+    }
+
+    #[test]
+    fn dual() {
+        let mut values = HashMap::new();
+        values.insert(Variable::Z, Dual::variable(3.0));
+        let expr = "z*z*z-2*z".parse::<Expr<Dual<f32>, FieldFunction, FieldOperator>>();
+        assert_eq!(
+            expr.unwrap().eval(&values),
+            Dual {
+                re: 21.0,
+                eps: 25.0
+            }
+        );
+    }
+
+    #[test]
+    fn complex_dual() {
+        let z = Complex { re: 0.5, im: -0.25 };
+        let mut values = HashMap::new();
+        values.insert(
+            Variable::Z,
+            Complex {
+                re: Dual::variable(z.re),
+                im: Dual::from(z.im),
+            },
+        );
+        let fz = "sin(z)/z"
+            .parse::<Expr<Complex<Dual<f32>>, ComplexFunction, ComplexOperator>>()
+            .unwrap()
+            .eval(&values);
+        let df = Complex {
+            re: fz.re.eps,
+            im: fz.im.eps,
+        };
+        assert_close(df, (z.cos() * z - z.sin()) / (z * z));
+    }
+
+    #[test]
+    fn error_closing_paren() {
+        let err = parse_error("(z+1");
+        assert_eq!(err.offset, 4);
+        assert_eq!(err.span, 4..4);
+        assert_eq!(err.expected, vec![Expected::ClosingParen]);
+        assert_eq!(parse_error("sqrt(z").expected, vec![Expected::ClosingParen]);
+    }
+
+    #[test]
+    fn error_operand() {
+        let err = parse_error("");
+        assert_eq!((err.offset, err.expected), (0, vec![Expected::Operand]));
+        let err = parse_error("*z");
+        assert_eq!((err.span, err.expected), (0..1, vec![Expected::Operand]));
+        let err = parse_error("exp(");
+        assert_eq!((err.offset, err.expected), (4, vec![Expected::Operand]));
+    }
+
+    #[test]
+    fn error_call() {
+        let err = parse_error("pow(z)");
+        assert_eq!((err.span, err.expected), (5..6, vec![Expected::Comma]));
+        let err = parse_error("pow z");
+        assert_eq!(
+            (err.offset, err.expected),
+            (4, vec![Expected::OpeningParen])
+        );
+    }
+
+    #[test]
+    fn error_span() {
+        let err = parse_error("(z)2ab");
+        assert_eq!(err.span, 3..6);
+        let err = parse_error("€*z");
+        assert_eq!(err.span, 0..3);
+        assert_eq!(err.to_string().lines().last(), Some("^"));
+    }
+
+    #[test]
+    fn error_message() {
+        assert_eq!(
+            parse_error("(z+1").to_string(),
+            "expected ')' at 4\n(z+1\n    ^"
+        );
+        assert_eq!(
+            parse_error("(*)").to_string(),
+            "expected a number, variable, function or '(' at 1\n(*)\n ^"
+        );
+        assert_eq!(
+            parse_error("z 2ab").to_string(),
+            "expected an operator, or end of input at 2\nz 2ab\n  ^^^"
+        );
+    }
+
+    #[test]
+    fn trailing_input() {
+        let err = parse_error("z*z+c)junk");
+        assert_eq!(err.offset, 5);
+        assert_eq!(err.span, 5..6);
+        assert_eq!(err.expected, vec![Expected::Operator, Expected::EndOfInput]);
+        assert_eq!(parse_error("z 2").offset, 2);
+        assert_eq!(parse_error("(z)2").offset, 3);
+        assert_eq!(parse_error("(z))").offset, 3);
+    }
+
+    #[test]
+    fn missing_operand() {
+        let err = parse_error("z+");
+        assert_eq!((err.offset, err.expected), (2, vec![Expected::Operand]));
+        let err = parse_error("2*(z-)");
+        assert_eq!((err.offset, err.expected), (5, vec![Expected::Operand]));
+        let err = parse_error("z*(z+1");
+        assert_eq!(
+            (err.offset, err.expected),
+            (6, vec![Expected::ClosingParen])
+        );
+        let err = parse_error("pow(z,2*)");
+        assert_eq!((err.offset, err.expected), (8, vec![Expected::Operand]));
+    }
+
+    #[test]
+    fn prefix() {
+        let (expr, rest) = ExprComplex::parse_prefix("z*z+c)junk").unwrap();
+        assert_eq!(expr, "z*z+c".parse().unwrap());
+        assert_eq!(rest, ")junk");
+        assert_eq!(ExprComplex::parse_prefix("z").unwrap().1, "");
+        assert!(ExprComplex::parse_prefix(")").is_err());
+    }
+
+    #[test]
+    fn whitespace() {
+        let z = Complex { re: 0.5, im: 0.25 };
+        let spaced = [
+            ("z * z + 1", "z*z+1"),
+            ("( z + 1 )", "(z+1)"),
+            (" z / 2 - i ", "z/2-i"),
+            ("- z", "-z"),
+            ("exp ( z )", "exp(z)"),
+            ("sin z", "sin(z)"),
+            ("pow ( z , 2 )", "pow(z,2)"),
+            ("\tz\n*\r\nz", "z*z"),
+            ("z\u{a0}*\u{a0}z\u{2009}+\u{3000}1", "z*z+1"),
+        ];
+        for (input, expected) in spaced {
+            assert_eq!(
+                input.parse::<ExprComplex>(),
+                expected.parse::<ExprComplex>(),
+                "{input:?}"
+            );
+        }
+        assert_close(
+            parse_and_eval_complex(" ( z - 1 ) * ( z + 1 ) ", z),
+            z * z - Complex::from(1.0),
+        );
+    }
+
+    #[test]
+    fn whitespace_errors() {
+        let err = parse_error("z +  ");
+        assert_eq!((err.offset, err.expected), (5, vec![Expected::Operand]));
+        let err = parse_error("( z + 1 ");
+        assert_eq!(
+            (err.offset, err.expected),
+            (8, vec![Expected::ClosingParen])
+        );
+        let err = parse_error("z * z )");
+        assert_eq!((err.offset, err.span), (6, 6..7));
+        let err = parse_error("pow(z 2)");
+        assert_eq!((err.offset, err.expected), (6, vec![Expected::Comma]));
+    }
+
+    #[test]
+    fn power() {
+        let mut values = HashMap::new();
+        values.insert(Variable::Z, Complex::from(2.0));
+        let eval = |input: &str| input.parse::<ExprComplex>().unwrap().eval(&values);
+        assert_eq!(eval("z^3"), Complex::from(8.0));
+        assert_eq!(eval("2^3^2"), Complex::from(512.0));
+        assert_eq!(eval("-z^2"), Complex::from(-4.0));
+        assert_eq!(eval("(-z)^2"), Complex::from(4.0));
+        assert_eq!(eval("z^-1"), Complex::from(0.5));
+        assert_eq!(eval("3*z^2/2"), Complex::from(6.0));
+        assert_eq!(eval("z ^ 2 ^ -1"), Complex::from(2.0_f32.sqrt()));
+        assert_eq!(eval("pow(z,2)^2"), Complex::from(16.0));
+    }
+
+    #[test]
+    fn power_structure() {
+        let parse = |input: &str| input.parse::<ExprComplex>().unwrap();
+        let z = || Expr::Variable(Variable::Z);
+        let two = || Expr::Constant(Complex::from(2.0));
+        let pow = |l, r| Expr::operator(ComplexOperator::Pow, l, r);
+        let neg = |e| Expr::function(FieldFunction::Neg, e);
+        assert_eq!(parse("-z^2"), neg(pow(z(), two())));
+        assert_eq!(parse("z^2^z"), pow(z(), pow(two(), z())));
+        assert_eq!(parse("z^-2"), pow(z(), Expr::Constant(Complex::from(-2.0))));
+        assert_eq!(parse("-2^z"), neg(pow(two(), z())));
+        assert_eq!(
+            parse("exp(z)^2"),
+            pow(Expr::function(ComplexFunction::Exp, z()), two())
+        );
+        assert_eq!(
+            parse("z*-z^2"),
+            Expr::operator(FieldOperator::Mul, z(), neg(pow(z(), two())))
+        );
+    }
+
+    #[test]
+    fn constant_literals() {
+        let parse = |input: &str| input.parse::<ExprComplex>().unwrap();
+        let ct = |re, im| Expr::Constant(Complex { re, im });
+        assert_eq!(parse("-2"), ct(-2.0, 0.0));
+        assert_eq!(parse("1.5-2i"), ct(1.5, -2.0));
+        assert_eq!(parse("-1 + i"), ct(-1.0, 1.0));
+        assert_eq!(parse("-2i"), ct(0.0, -2.0));
+        assert_eq!(
+            parse("1.5-(2i)"),
+            Expr::operator(FieldOperator::Sub, ct(1.5, 0.0), ct(0.0, 2.0))
+        );
+        assert_eq!(
+            parse("1+2i+3i"),
+            Expr::operator(FieldOperator::Add, ct(1.0, 2.0), ct(0.0, 3.0))
+        );
+        assert_eq!(parse("-inf"), ct(f32::NEG_INFINITY, 0.0));
+        assert_eq!(parse("1-infi"), ct(1.0, f32::NEG_INFINITY));
+        let Expr::Constant(nan) = parse("nan+nani") else {
+            panic!("not a constant");
+        };
+        assert!(nan.re.is_nan() && nan.im.is_nan());
+        let real = "nan".parse::<Expr<f32, FieldFunction, FieldOperator>>();
+        assert!(matches!(real, Ok(Expr::Constant(x)) if x.is_nan()));
+    }
+
+    #[test]
+    fn power_integer_exponent() {
+        let z = Complex { re: 1.0, im: 1.0 };
+        assert_eq!(parse_and_eval_complex("z^8", z), Complex::from(16.0));
+        assert_eq!(
+            parse_and_eval_complex("z^0", Complex::O),
+            Complex::from(1.0)
+        );
+        assert_close(
+            parse_and_eval_complex("z^0.5", Complex::from(-4.0)),
+            Complex::I * 2.0,
+        );
+    }
+
+    #[test]
+    fn power_errors() {
+        let err = parse_error("z^");
+        assert_eq!((err.offset, err.expected), (2, vec![Expected::Operand]));
+        assert!("z^2"
+            .parse::<Expr<f32, FieldFunction, FieldOperator>>()
+            .is_err());
+    }
+
+    #[test]
+    fn implicit_multiplication() {
+        let z = Complex { re: 0.5, im: 0.25 };
+        let one = Complex::from(1.0);
+        assert_eq!(parse_and_eval_complex("2z", z), z * 2.0);
+        assert_eq!(parse_and_eval_complex("2 z", z), z * 2.0);
+        assert_eq!(parse_and_eval_complex("z(z+1)", z), z * (z + one));
+        assert_eq!(
+            parse_and_eval_complex("(z-1)(z+1)", z),
+            (z - one) * (z + one)
+        );
+        assert_eq!(
+            parse_and_eval_complex("2sin(z)cos(z)", z),
+            z.sin() * 2.0 * z.cos()
+        );
+        assert_eq!("z c".parse::<ExprComplex>(), "z*c".parse::<ExprComplex>());
+    }
+
+    #[test]
+    fn implicit_multiplication_precedence() {
+        let parse = |input: &str| input.parse::<ExprComplex>().unwrap();
+        assert_eq!(parse("1/2z"), parse("(1/2)*z"));
+        assert_eq!(parse("2z^2"), parse("2*(z^2)"));
+        assert_eq!(parse("z-1"), parse("z - 1"));
+        assert_eq!(parse("2(z)-(1)"), parse("2*z-1"));
+        assert_eq!(parse("sin 2z"), parse("sin(2)*z"));
+        assert_eq!(parse("2inv(z)"), parse("2*inv(z)"));
+    }
+
+    #[test]
+    fn imaginary_literals() {
+        let literal = |input: &str| match input.parse::<ExprComplex>() {
+            Ok(Expr::Constant(ct)) => ct,
+            other => panic!("{input:?} parsed as {other:?}"),
+        };
+        assert_eq!(literal("3i"), Complex { re: 0.0, im: 3.0 });
+        assert_eq!(
+            literal("2.5e-3i"),
+            Complex {
+                re: 0.0,
+                im: 2.5e-3
+            }
+        );
+        assert_eq!(literal(".5i"), Complex { re: 0.0, im: 0.5 });
+        assert_eq!(literal("i"), Complex::I);
+        assert_eq!(
+            parse_and_eval_complex("1+2i", Complex::O),
+            Complex { re: 1.0, im: 2.0 }
+        );
+        assert_eq!(
+            parse_and_eval_complex("2i z", Complex::I),
+            Complex::from(-2.0)
+        );
+        assert_eq!("2iz".parse::<ExprComplex>(), "2*iz".parse::<ExprComplex>());
+    }
+
+    #[test]
+    fn scientific_notation() {
+        assert_eq!(parse_and_eval::<f32>("2.5e-3"), 2.5e-3);
+        assert_eq!(parse_and_eval::<f32>("1E3+1"), 1001.0);
+        assert_eq!(parse_and_eval::<f64>("6.02e+23"), 6.02e23);
+        assert_eq!(parse_and_eval::<f32>("2."), 2.0);
+        assert!("1e"
+            .parse::<Expr<f32, FieldFunction, FieldOperator>>()
+            .is_ok());
+    }
+
+    #[test]
+    fn named_constants() {
+        use std::f64::consts::{E, PI, TAU};
+        assert_eq!(parse_and_eval::<f64>("pi"), PI);
+        assert_eq!(parse_and_eval::<f64>("e"), E);
+        assert_eq!(parse_and_eval::<f64>("tau"), TAU);
+        assert_eq!(parse_and_eval::<f64>("2pi"), 2.0 * PI);
+        assert_eq!(parse_and_eval::<f64>("e e"), E * E);
+        assert_close(
+            parse_and_eval_complex("exp(pi i)", Complex::O),
+            Complex::from(-1.0),
+        );
+        assert_close(
+            parse_and_eval_complex("e^z", Complex::from(2.0)),
+            Complex::from(std::f32::consts::E).powi(2),
+        );
+        let var = |name: &str| Expr::Variable(Variable::new(name));
+        assert_eq!("pix".parse::<ExprComplex>(), Ok(var("pix")));
+        assert_eq!("e_1".parse::<ExprComplex>(), Ok(var("e_1")));
+    }
+
+    /// Where two readings are possible, the one documented on the parser
+    /// wins.
+    #[test]
+    fn ambiguities() {
+        // an exponent needs digits: `2e` is `2*e`, `2e-3` is a literal
+        assert_eq!(parse_and_eval::<f64>("2e"), 2.0 * std::f64::consts::E);
+        assert_eq!(parse_and_eval::<f64>("2e-3"), 0.002);
+        assert_eq!(parse_and_eval::<f64>("2e-z"), 2.0 * std::f64::consts::E);
+        // `exp` is the function, not `e*xp`
+        assert_close(
+            parse_and_eval_complex("exp(z)", Complex::from(1.0)),
+            Complex::from(std::f32::consts::E),
+        );
+        // a digit after an operand is an error rather than a factor
+        assert_eq!(parse_error("(z)2").offset, 3);
+        assert_eq!(parse_error("2 3").offset, 2);
+        // identifiers are read whole, `zc` is a variable and `sinz` is not
+        // `sin(z)`
+        let parse = |input: &str| input.parse::<ExprComplex>().unwrap();
+        assert_eq!(parse("zc"), Expr::Variable(Variable::new("zc")));
+        assert_eq!(parse("sinz"), Expr::Variable(Variable::new("sinz")));
+        assert_eq!(parse("sin z"), parse("sin(z)"));
+        // `-` is always subtraction between operands
+        assert_eq!(
+            parse_and_eval_complex("z -1", Complex::O),
+            Complex::from(-1.0)
+        );
+    }
+
+    #[test]
+    fn definitions() {
+        let z = Complex { re: 0.5, im: 0.25 };
+        let one = Complex::from(1.0);
+        let g = |w: Complex<f32>| w * w + one;
+        assert_close(
+            parse_and_eval_complex("g(w) = w*w+1; f(z) = g(z)/g(z-1)", z),
+            g(z) / g(z - one),
+        );
+        assert_close(
+            parse_and_eval_complex("g(w) = w*w+1; g(z)/g(z-1);", z),
+            g(z) / g(z - one),
+        );
+        assert_close(
+            parse_and_eval_complex("g(w) = w+1; h(a, b) = g(a)*b; h(z, 2)", z),
+            (z + one) * 2.0,
+        );
+        // the parameter of the last definition is bound to `z`
+        assert_close(parse_and_eval_complex("f(w) = w^2", z), z * z);
+        assert_eq!(
+            "f(z) = z^2".parse::<ExprComplex>(),
+            "z^2".parse::<ExprComplex>()
+        );
+        // definitions see the globals, not the locals of the caller
+        assert_close(
+            parse_and_eval_complex("g(w) = w+z; let z = 1 in g(0)", z),
+            z,
+        );
+    }
+
+    #[test]
+    fn let_bindings() {
+        let z = Complex { re: 0.5, im: 0.25 };
+        let one = Complex::from(1.0);
+        let w = z * z + one;
+        assert_close(
+            parse_and_eval_complex("let w = z*z+1 in w/(w-1)+z", z),
+            w / (w - one) + z,
+        );
+        assert_close(
+            parse_and_eval_complex("let w = z in let z = 2 in w*z", z),
+            z * 2.0,
+        );
+        assert_close(
+            parse_and_eval_complex("2*(let w = z in w)+1", z),
+            z * 2.0 + one,
+        );
+        assert_close(parse_and_eval_complex("exp(let w = z in w)", z), z.exp());
+        let parse = |input: &str| input.parse::<ExprComplex>().unwrap();
+        assert_eq!(parse("let w = z in w + 1"), parse("let w = z in (w + 1)"));
+        assert_eq!(parse("letter + inner").free_variables().len(), 2);
+    }
+
+    #[test]
+    fn comparisons() {
+        let z = Complex { re: 0.5, im: 0.25 };
+        let one = Complex::from(1.0);
+        assert_close(parse_and_eval_complex("if(abs(z) < 1, z, 1/z)", z), z);
+        assert_close(parse_and_eval_complex("if(abs(z) > 1, z, 1/z)", z), one / z);
+        assert_close(parse_and_eval_complex("if(z > 0, z-1, z+1)", z), z - one);
+        assert_close(parse_and_eval_complex("(z >= 0.5) + (z <= 0)", z), one);
+        let parse = |input: &str| input.parse::<ExprComplex>().unwrap();
+        assert_eq!(parse("z+1 < 2*z"), parse("(z+1) < (2*z)"));
+        assert_eq!(parse("if(z<1,z,1)"), parse("if (z < 1, z, 1)"));
+        assert_eq!(parse("ifz + zif").free_variables().len(), 2);
+        let err = parse_error("z < 1 < 2");
+        assert_eq!(err.offset, 6);
+        let err = parse_error("if(z, 1)");
+        assert_eq!((err.offset, err.expected), (7, vec![Expected::Comma]));
+    }
+
+    #[test]
+    fn definition_errors() {
+        let err = parse_error("g(w) = w; g");
+        assert_eq!(
+            (err.offset, err.expected),
+            (11, vec![Expected::OpeningParen])
+        );
+        let err = parse_error("g(w) = w; g(z, z)");
+        assert_eq!(
+            (err.offset, err.expected),
+            (13, vec![Expected::ClosingParen])
+        );
+        let err = parse_error("g(a, b) = a; g(z)");
+        assert_eq!((err.offset, err.expected), (16, vec![Expected::Comma]));
+        let err = parse_error("let = z in z");
+        assert_eq!((err.offset, err.expected), (4, vec![Expected::Name]));
+        let err = parse_error("let w z in z");
+        assert_eq!((err.offset, err.expected), (6, vec![Expected::Equals]));
+        let err = parse_error("let w = z");
+        assert_eq!((err.offset, err.expected), (9, vec![Expected::In]));
+        let err = parse_error("f(a, b, d) = a");
+        assert_eq!((err.offset, err.expected), (0, vec![Expected::Semicolon]));
+        // no recursion
+        let err = parse_error("g(w) = g(w); g(z)");
+        assert_eq!((err.offset, err.span), (7, 7..8));
+        assert_eq!(err.expected, [Expected::NonRecursive]);
+        let err = parse_error("f(z) = 1/(2*f (z))");
+        assert_eq!(err.offset, 12);
+        assert_eq!(
+            err.to_string(),
+            "expected a call of an earlier definition, not a recursive one at 12\n\
+             f(z) = 1/(2*f (z))\n            ^"
+        );
+        // but a redefinition calls the one it shadows
+        let expr = "g(w) = w+1; g(w) = g(w)*2; g(z)"
+            .parse::<ExprComplex>()
+            .unwrap();
+        assert_eq!(expr.definitions().len(), 2);
+    }
 }
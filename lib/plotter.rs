@@ -1,44 +1,92 @@
+use std::{collections::HashMap, rc::Rc};
+
 use crate::{
     algebra::{Complex, Zero},
-    expression::{ComplexFunction, Expr, ExprComplex, FieldOperator, Variable},
+    expression::{
+        ComplexFunction, ComplexOperator, Definition, Expr, ExprComplex, FieldFunction,
+        FieldOperator, Variable,
+    },
+    palette::{Palette, PaletteScale, Stop},
+    parser::ParseError,
+    render::{center_uniform, check_max_iter, DrawMode, SUBSAMPLE_ROOT},
+    scene::Scene,
 };
 use wasm_bindgen::prelude::*;
 use web_sys::{WebGl2RenderingContext as WebGl2, WebGlProgram, WebGlShader, WebGlUniformLocation};
 
-#[wasm_bindgen]
-#[derive(Clone, Copy)]
-pub enum DrawMode {
-    DomainColouring = 1,
-    ParameterStability = 2,
-    Julia = 3,
-}
-
 #[wasm_bindgen]
 pub struct JsComplex {
     pub re: f32,
     pub im: f32,
 }
 
+/// A [`ParseError`] for JS. Offsets are in UTF-16 code units, so they index
+/// the JS string that was parsed.
+#[wasm_bindgen]
+pub struct JsParseError {
+    pub offset: usize,
+    pub start: usize,
+    pub end: usize,
+    message: String,
+}
+
+#[wasm_bindgen]
+impl JsParseError {
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+}
+
+impl From<ParseError> for JsParseError {
+    fn from(err: ParseError) -> Self {
+        let utf16 = |end: usize| err.input[..end].encode_utf16().count();
+        JsParseError {
+            offset: utf16(err.offset),
+            start: utf16(err.span.start),
+            end: utf16(err.span.end),
+            message: err.to_string(),
+        }
+    }
+}
+
 enum State {
     Loading,
     Invalid,
     Valid,
 }
 
-const SUBSAMPLE_ROOT: i32 = 2; // subsample 4 points per pixel
+/// Float precision of the fragment shader. `High` is slower on some mobile
+/// GPUs but allows deeper zooms.
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub enum Precision {
+    Medium = 1,
+    High = 2,
+}
 
 #[wasm_bindgen]
 pub struct Plotter {
     ctx: WebGl2,
 
     state: State,
+    /// The source of `function`, definitions included.
+    function_str: String,
     function: ExprComplex,
 
     draw_mode: DrawMode,
     max_iter: i32,
+    precision: Precision,
     xscale: f32,
+    /// The `center` uniform, see [`center_uniform`].
     center: Complex<f32>,
     parameter_c: Complex<f32>,
+    /// Free variables of `function` other than `z` and `c`, uniform
+    /// `param_{i}` in the shader.
+    parameters: Vec<Variable>,
+    /// Values set from JS by name, kept across functions. Unset ones are 0.
+    parameter_values: HashMap<String, Complex<f32>>,
+    palette: Palette,
 
     u_draw_mode: Option<WebGlUniformLocation>,
     u_max_iter: Option<WebGlUniformLocation>,
@@ -47,6 +95,13 @@ pub struct Plotter {
     u_center: Option<WebGlUniformLocation>,
     u_parameter_c: Option<WebGlUniformLocation>,
     u_subsample: Option<WebGlUniformLocation>,
+    u_parameters: Vec<Option<WebGlUniformLocation>>,
+    u_palette: Option<WebGlUniformLocation>,
+    u_palette_len: Option<WebGlUniformLocation>,
+    u_palette_log: Option<WebGlUniformLocation>,
+    u_palette_cyclic: Option<WebGlUniformLocation>,
+    u_palette_offset: Option<WebGlUniformLocation>,
+    u_palette_density: Option<WebGlUniformLocation>,
 
     last_dragged: Option<(i32, i32)>,
 }
@@ -59,6 +114,7 @@ impl Plotter {
         function: &str,
         draw_mode: DrawMode,
         max_iter: i32,
+        precision: Precision,
     ) -> Result<Plotter, JsValue> {
         let buffer = ctx.create_buffer().ok_or("Failed to create buffer")?;
         ctx.bind_buffer(WebGl2::ARRAY_BUFFER, Some(&buffer));
@@ -81,12 +137,17 @@ impl Plotter {
             ctx,
 
             state: State::Invalid,
+            function_str: String::new(),
             function: ExprComplex::Constant(Complex::O),
             draw_mode,
             max_iter,
+            precision,
             xscale: 1.0,
             center: Complex::O,
             parameter_c: Complex::O,
+            parameters: vec![],
+            parameter_values: HashMap::new(),
+            palette: Palette::default(),
 
             u_draw_mode: None,
             u_max_iter: None,
@@ -96,15 +157,33 @@ impl Plotter {
             last_dragged: None,
             u_parameter_c: None,
             u_subsample: None,
+            u_parameters: vec![],
+            u_palette: None,
+            u_palette_len: None,
+            u_palette_log: None,
+            u_palette_cyclic: None,
+            u_palette_offset: None,
+            u_palette_density: None,
         };
         res.set_function(function)?;
+        res.set_max_iter(max_iter)?;
         Ok(res)
     }
 
     #[wasm_bindgen]
     pub fn set_function(&mut self, function: &str) -> Result<(), JsValue> {
-        let new_function = function.parse::<ExprComplex>()?;
+        let new_function = function
+            .parse::<ExprComplex>()
+            .map_err(JsParseError::from)?
+            .simplify();
+        self.function_str = function.to_string();
         if self.function != new_function {
+            self.parameters = new_function
+                .free_variables()
+                .into_iter()
+                .filter(|&var| *var != Variable::Z && *var != Variable::C)
+                .cloned()
+                .collect();
             self.function = new_function;
             self.load_function()?;
         }
@@ -117,10 +196,13 @@ impl Plotter {
         self.state = State::Invalid;
     }
 
+    /// Fails if `max_iter` is less than 1, leaving it unchanged.
     #[wasm_bindgen]
-    pub fn set_max_iter(&mut self, max_iter: i32) {
+    pub fn set_max_iter(&mut self, max_iter: i32) -> Result<(), JsValue> {
+        check_max_iter(max_iter)?;
         self.max_iter = max_iter;
         self.state = State::Invalid;
+        Ok(())
     }
 
     #[wasm_bindgen]
@@ -138,6 +220,148 @@ impl Plotter {
         self.state = State::Invalid;
     }
 
+    /// Names of the free parameters of the function, in name order.
+    #[wasm_bindgen]
+    pub fn parameters(&self) -> Vec<String> {
+        self.parameters
+            .iter()
+            .map(|var| var.name().to_string())
+            .collect()
+    }
+
+    /// The current view, to restore it later with [`Plotter::apply_scene`].
+    #[wasm_bindgen]
+    pub fn scene(&self) -> Scene {
+        Scene {
+            version: Scene::VERSION,
+            function: self.function_str.clone(),
+            draw_mode: self.draw_mode,
+            max_iter: self.max_iter,
+            xscale: self.xscale,
+            center: center_uniform(self.center),
+            parameter_c: self.parameter_c,
+            parameters: (self.parameter_values.iter())
+                .map(|(name, &value)| (name.clone(), value))
+                .collect(),
+            palette: self.palette.clone(),
+        }
+    }
+
+    /// Restores a view, all or nothing: nothing changes if the function does
+    /// not parse or `max_iter` is less than 1.
+    #[wasm_bindgen]
+    pub fn apply_scene(&mut self, scene: &Scene) -> Result<(), JsValue> {
+        check_max_iter(scene.max_iter)?;
+        self.set_function(&scene.function)?;
+        self.draw_mode = scene.draw_mode;
+        self.max_iter = scene.max_iter;
+        self.xscale = scene.xscale;
+        self.center = center_uniform(scene.center);
+        self.parameter_c = scene.parameter_c;
+        self.parameter_values = (scene.parameters.iter())
+            .map(|(name, &value)| (name.clone(), value))
+            .collect();
+        self.palette = scene.palette.clone();
+        self.state = State::Invalid;
+        Ok(())
+    }
+
+    /// The current view as a PNG of any size, rendered on the CPU with the
+    /// view in its metadata, see [`crate::export::write_png`].
+    #[cfg(feature = "png")]
+    #[wasm_bindgen]
+    pub fn export_png(&self, width: u32, height: u32) -> Result<Vec<u8>, JsValue> {
+        let viewport = crate::render::Viewport {
+            center: center_uniform(self.center),
+            xscale: self.xscale,
+            width,
+            height,
+        };
+        let mut parameters = self.parameter_values.clone();
+        parameters.insert("c".to_string(), self.parameter_c);
+        let mut png = vec![];
+        crate::export::write_png(
+            &mut png,
+            &self.function,
+            &self.function_str,
+            self.draw_mode,
+            &viewport,
+            self.max_iter,
+            &parameters,
+            &self.palette,
+        )
+        .map_err(|err| err.to_string())?;
+        Ok(png)
+    }
+
+    /// Sets a parameter by name, whether or not the current function uses
+    /// it. Only updates a uniform, the shader is not recompiled.
+    #[wasm_bindgen]
+    pub fn set_parameter(&mut self, name: &str, re: f32, im: f32) {
+        self.parameter_values
+            .insert(name.to_string(), Complex { re, im });
+        self.state = State::Invalid;
+    }
+
+    /// Names of the built-in palettes, for [`Plotter::set_palette`].
+    #[wasm_bindgen]
+    pub fn palette_names() -> Vec<String> {
+        Palette::NAMES.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// Colours the escape-time modes with a built-in gradient, keeping the
+    /// mapping. Palettes are uniforms, changing them does not recompile the
+    /// shader.
+    #[wasm_bindgen]
+    pub fn set_palette(&mut self, name: &str) -> Result<(), JsValue> {
+        let stops = Palette::named(name)?.stops().to_vec();
+        self.palette.set_stops(stops)?;
+        self.state = State::Invalid;
+        Ok(())
+    }
+
+    /// Colours the escape-time modes with a gradient of `position, red,
+    /// green, blue` stops, all in `[0, 1]` and positions increasing.
+    #[wasm_bindgen]
+    pub fn set_palette_stops(&mut self, stops: &[f32]) -> Result<(), JsValue> {
+        if stops.len() % 4 != 0 {
+            return Err("palette stops are position, red, green, blue".into());
+        }
+        let stops = (stops.chunks_exact(4))
+            .map(|stop| Stop {
+                position: stop[0],
+                color: [stop[1], stop[2], stop[3]],
+            })
+            .collect();
+        self.palette.set_stops(stops)?;
+        self.state = State::Invalid;
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn set_palette_scale(&mut self, scale: PaletteScale) {
+        self.palette.scale = scale;
+        self.state = State::Invalid;
+    }
+
+    #[wasm_bindgen]
+    pub fn set_palette_cyclic(&mut self, cyclic: bool) {
+        self.palette.cyclic = cyclic;
+        self.state = State::Invalid;
+    }
+
+    #[wasm_bindgen]
+    pub fn set_palette_offset(&mut self, offset: f32) {
+        self.palette.offset = offset;
+        self.state = State::Invalid;
+    }
+
+    #[wasm_bindgen]
+    pub fn set_palette_density(&mut self, density: f32) {
+        self.palette.density = density;
+        self.state = State::Invalid;
+    }
+
     #[wasm_bindgen]
     pub fn zoom(&mut self, factor: f32) {
         self.xscale /= factor;
@@ -202,6 +426,27 @@ impl Plotter {
         );
         self.ctx
             .uniform1i(self.u_subsample.as_ref(), SUBSAMPLE_ROOT);
+        for (var, location) in self.parameters.iter().zip(&self.u_parameters) {
+            let value = self.parameter_values.get(var.name()).unwrap_or(&Complex::O);
+            self.ctx
+                .uniform2fv_with_f32_array(location.as_ref(), &[value.re, value.im]);
+        }
+        self.ctx
+            .uniform4fv_with_f32_array(self.u_palette.as_ref(), &self.palette.uniform());
+        self.ctx.uniform1i(
+            self.u_palette_len.as_ref(),
+            self.palette.stops().len() as i32,
+        );
+        self.ctx.uniform1i(
+            self.u_palette_log.as_ref(),
+            (self.palette.scale == PaletteScale::Log) as i32,
+        );
+        self.ctx
+            .uniform1i(self.u_palette_cyclic.as_ref(), self.palette.cyclic as i32);
+        self.ctx
+            .uniform1f(self.u_palette_offset.as_ref(), self.palette.offset);
+        self.ctx
+            .uniform1f(self.u_palette_density.as_ref(), self.palette.density);
         self.ctx.clear_color(0.0, 0.0, 0.0, 1.0);
         self.ctx.clear(WebGl2::COLOR_BUFFER_BIT);
 
@@ -236,13 +481,7 @@ impl Plotter {
         )?;
 
         // generate and compile fragment shader
-        let mut fragment_src = include_str!("shader/plotter.frag").to_string();
-        let (begin_mark, end_mark) = ("/*BEGIN REPLACE*/", "/*END REPLACE*/");
-        if let (Some(l), Some(r)) = (fragment_src.find(begin_mark), fragment_src.find(end_mark)) {
-            let mut snippet = String::new();
-            build_snippet(&mut snippet, &self.function);
-            fragment_src.replace_range(l..r + end_mark.len(), &snippet);
-        }
+        let fragment_src = fragment_source(&self.function, &self.parameters, self.precision);
         let frag_shader = compile_shader(&self.ctx, WebGl2::FRAGMENT_SHADER, &fragment_src)?;
         //log::info!("Using fragment shader\n: {fragment_src} ");
 
@@ -264,6 +503,18 @@ impl Plotter {
         self.u_resolution = self.ctx.get_uniform_location(&program, "resolution");
         self.u_parameter_c = self.ctx.get_uniform_location(&program, "parameter_c");
         self.u_subsample = self.ctx.get_uniform_location(&program, "n_subsample");
+        self.u_parameters = (0..self.parameters.len())
+            .map(|i| {
+                self.ctx
+                    .get_uniform_location(&program, &format!("param_{i}"))
+            })
+            .collect();
+        self.u_palette = self.ctx.get_uniform_location(&program, "palette");
+        self.u_palette_len = self.ctx.get_uniform_location(&program, "palette_len");
+        self.u_palette_log = self.ctx.get_uniform_location(&program, "palette_log");
+        self.u_palette_cyclic = self.ctx.get_uniform_location(&program, "palette_cyclic");
+        self.u_palette_offset = self.ctx.get_uniform_location(&program, "palette_offset");
+        self.u_palette_density = self.ctx.get_uniform_location(&program, "palette_density");
         // invalidate plane
         self.state = State::Invalid;
         Ok(())
@@ -316,43 +567,495 @@ fn compile_shader(context: &WebGl2, shader_type: u32, source: &str) -> Result<We
     }
 }
 
-fn build_snippet(ret: &mut String, expr: &ExprComplex) {
-    match expr {
-        Expr::Variable(var) => {
-            let str = match var {
-                Variable::C => "c",
-                Variable::Z => "z",
-            };
-            ret.push_str(str);
+/// The fragment shader computing `function`, with a `param_{i}` uniform for
+/// each of its `parameters`.
+fn fragment_source(
+    function: &ExprComplex,
+    parameters: &[Variable],
+    precision: Precision,
+) -> String {
+    let mut src = include_str!("shader/plotter.frag").to_string();
+    let precision = match precision {
+        Precision::Medium => "precision mediump float;",
+        Precision::High => "precision highp float;",
+    };
+    replace_marked(&mut src, "PRECISION", precision);
+    let degree = function.degree(&Variable::Z).unwrap_or(0);
+    replace_marked(&mut src, "DEGREE", &glsl_float(degree as f32));
+    let mut snippet = Snippet::new(parameters);
+    let body = snippet.function_body(function, &[]);
+    let mut definitions = String::new();
+    for i in 0..parameters.len() {
+        definitions.push_str(&format!("uniform vec2 param_{i};\n"));
+    }
+    for helper in &snippet.helpers {
+        definitions.push_str(helper);
+    }
+    replace_marked(&mut src, "DEFINITIONS", &definitions);
+    replace_marked(&mut src, "REPLACE", &body);
+    src
+}
+
+/// A GLSL ES 3.00 float constant equal to `x`. Finite values are written in
+/// the shortest form that reads back as `x`, always with a `.` or an
+/// exponent, since `1` would be an `int`. GLSL has no literals for infinities
+/// and NaN, so those are rebuilt from their bits.
+fn glsl_float(x: f32) -> String {
+    match x.is_finite() {
+        // `Debug` writes `1.0`, `-0.0`, `1e20` or `1.5e-7`
+        true => format!("{x:?}"),
+        false => format!("uintBitsToFloat({:#x}u)", x.to_bits()),
+    }
+}
+
+/// Replaces `/*BEGIN {name}*/.../*END {name}*/` in `src`.
+fn replace_marked(src: &mut String, name: &str, with: &str) {
+    let (begin_mark, end_mark) = (format!("/*BEGIN {name}*/"), format!("/*END {name}*/"));
+    if let (Some(l), Some(r)) = (src.find(&begin_mark), src.find(&end_mark)) {
+        src.replace_range(l..r + end_mark.len(), with);
+    }
+}
+
+/// GLSL code generation. Lets and calls become helper functions
+/// `vec2 helper_{k}(vec2 z, vec2 c, vec2 a0, ...)`, with `z` and `c` passed
+/// through for the definitions, which see the globals. The other arguments
+/// are the parameters of a definition, or the let-bound variable followed by
+/// the locals that the body of the let uses.
+struct Snippet<'a> {
+    parameters: &'a [Variable],
+    /// Each helper comes after those it calls.
+    helpers: Vec<String>,
+    /// Index of each helper by its code after the name.
+    helper_ids: HashMap<String, usize>,
+    /// Name of the helper of each definition generated so far.
+    definitions: HashMap<*const Definition<Complex<f32>, ComplexFunction, ComplexOperator>, String>,
+}
+
+/// GLSL names of the variables bound by lets and definitions, innermost last.
+type Locals<'e> = [(&'e Variable, String)];
+
+/// Generated code, with the compound subexpressions as nodes of a [`Dag`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Piece {
+    Code(String),
+    Node(usize),
+}
+
+/// The distinct subexpressions of a GLSL function, children first. Every
+/// name in the code is resolved, so equal code computes equal values, and
+/// those with more than one parent are computed once into a temporary
+/// `t{k}`.
+#[derive(Default)]
+struct Dag {
+    /// The code of each node and its number of distinct parents.
+    nodes: Vec<(Vec<Piece>, usize)>,
+    ids: HashMap<Vec<Piece>, usize>,
+}
+
+impl Dag {
+    fn intern(&mut self, code: Vec<Piece>) -> Piece {
+        if let Some(&id) = self.ids.get(&code) {
+            return Piece::Node(id);
         }
-        Expr::Constant(ct) => {
-            let Complex { re, im } = ct;
-            ret.push_str(&format!("vec2({re},{im})"));
+        for piece in &code {
+            if let Piece::Node(child) = piece {
+                self.nodes[*child].1 += 1;
+            }
         }
-        Expr::Function(fun, e) => {
-            let fun_str = match fun {
-                ComplexFunction::Re => "re",
-                ComplexFunction::Im => "im",
-                ComplexFunction::Abs => "abs",
-            };
-            ret.push_str(fun_str);
-            ret.push('(');
-            build_snippet(ret, e);
-            ret.push(')');
+        let id = self.nodes.len();
+        self.nodes.push((code.clone(), 0));
+        self.ids.insert(code, id);
+        Piece::Node(id)
+    }
+
+    /// The declarations of the temporaries, then `return root;`. The
+    /// temporaries are computed even when only used by the branch of an `if`
+    /// that is not taken, as GPUs mostly do for `?:` anyway.
+    fn statements(&self, root: &Piece) -> String {
+        let mut names = vec![None; self.nodes.len()];
+        let mut temporaries = 0;
+        let mut ret = String::new();
+        for (id, (code, parents)) in self.nodes.iter().enumerate() {
+            if *parents > 1 {
+                let name = format!("t{temporaries}");
+                temporaries += 1;
+                ret.push_str(&format!("vec2 {name} = "));
+                self.render(&mut ret, code, &names);
+                ret.push_str(";\n    ");
+                names[id] = Some(name);
+            }
+        }
+        ret.push_str("return ");
+        self.render(&mut ret, std::slice::from_ref(root), &names);
+        ret.push(';');
+        ret
+    }
+
+    fn render(&self, ret: &mut String, code: &[Piece], names: &[Option<String>]) {
+        for piece in code {
+            match piece {
+                Piece::Code(code) => ret.push_str(code),
+                Piece::Node(id) => match &names[*id] {
+                    Some(name) => ret.push_str(name),
+                    None => self.render(ret, &self.nodes[*id].0, names),
+                },
+            }
+        }
+    }
+}
+
+/// Appends `s` to `code`, merging it with the preceding text so that equal
+/// code has equal pieces.
+fn push_code(code: &mut Vec<Piece>, s: &str) {
+    match code.last_mut() {
+        Some(Piece::Code(last)) => last.push_str(s),
+        _ => code.push(Piece::Code(s.to_string())),
+    }
+}
+
+fn push_piece(code: &mut Vec<Piece>, piece: Piece) {
+    match piece {
+        Piece::Code(s) => push_code(code, &s),
+        node => code.push(node),
+    }
+}
+
+impl<'a> Snippet<'a> {
+    fn new(parameters: &'a [Variable]) -> Self {
+        Snippet {
+            parameters,
+            helpers: vec![],
+            helper_ids: HashMap::new(),
+            definitions: HashMap::new(),
+        }
+    }
+
+    /// Statements of a GLSL function returning `expr`.
+    fn function_body<'e>(&mut self, expr: &'e ExprComplex, locals: &Locals<'e>) -> String {
+        let mut dag = Dag::default();
+        let root = self.build(&mut dag, expr, locals);
+        dag.statements(&root)
+    }
+
+    /// Code of `expr`, a variable or constant as is and anything else
+    /// interned in `dag`.
+    fn build<'e>(&mut self, dag: &mut Dag, expr: &'e ExprComplex, locals: &Locals<'e>) -> Piece {
+        let mut code = vec![];
+        match expr {
+            Expr::Variable(var) => {
+                return Piece::Code(match locals.iter().rev().find(|(local, _)| *local == var) {
+                    Some((_, name)) => name.clone(),
+                    None => match self.parameters.iter().position(|p| p == var) {
+                        Some(i) => format!("param_{i}"),
+                        // `z` and `c`, arguments of `f` and of every helper
+                        None => var.name().to_string(),
+                    },
+                });
+            }
+            Expr::Constant(ct) => {
+                let Complex { re, im } = ct;
+                return Piece::Code(format!("vec2({},{})", glsl_float(*re), glsl_float(*im)));
+            }
+            Expr::Function(fun, e) => {
+                let fun_str = match fun {
+                    ComplexFunction::Field(FieldFunction::Neg) => "neg",
+                    ComplexFunction::Field(FieldFunction::Inv) => "inv",
+                    ComplexFunction::Re => "re",
+                    ComplexFunction::Im => "im",
+                    ComplexFunction::Abs => "cabs",
+                    ComplexFunction::Exp => "cexp",
+                    ComplexFunction::Log => "clog",
+                    ComplexFunction::Sqrt => "csqrt",
+                    ComplexFunction::Sin => "csin",
+                    ComplexFunction::Cos => "ccos",
+                    ComplexFunction::Tan => "ctan",
+                    ComplexFunction::Sinh => "csinh",
+                    ComplexFunction::Cosh => "ccosh",
+                    ComplexFunction::Tanh => "ctanh",
+                    ComplexFunction::Asin => "casin",
+                    ComplexFunction::Atan => "catan",
+                };
+                push_code(&mut code, fun_str);
+                push_code(&mut code, "(");
+                push_piece(&mut code, self.build(dag, e, locals));
+                push_code(&mut code, ")");
+            }
+            Expr::Operator(op, lhs, rhs) => {
+                // constant integer powers by repeated squaring
+                let n = match (op, rhs.as_ref()) {
+                    (ComplexOperator::Pow, Expr::Constant(ct)) => ct.as_integer(),
+                    _ => None,
+                };
+                if let Some(n) = n {
+                    push_code(&mut code, "cpowi(");
+                    push_piece(&mut code, self.build(dag, lhs, locals));
+                    push_code(&mut code, &format!(",{n})"));
+                } else {
+                    let op_str = match op {
+                        ComplexOperator::Field(FieldOperator::Add) => "add",
+                        ComplexOperator::Field(FieldOperator::Sub) => "sub",
+                        ComplexOperator::Field(FieldOperator::Mul) => "mul",
+                        ComplexOperator::Field(FieldOperator::Div) => "div",
+                        ComplexOperator::Pow => "cpow",
+                        ComplexOperator::Less => "clt",
+                        ComplexOperator::LessEq => "cle",
+                        ComplexOperator::Greater => "cgt",
+                        ComplexOperator::GreaterEq => "cge",
+                    };
+                    push_code(&mut code, op_str);
+                    push_code(&mut code, "(");
+                    push_piece(&mut code, self.build(dag, lhs, locals));
+                    push_code(&mut code, ",");
+                    push_piece(&mut code, self.build(dag, rhs, locals));
+                    push_code(&mut code, ")");
+                }
+            }
+            Expr::Let(var, e, body) => {
+                let free = body.free_variables();
+                let mut captured: Vec<&(&Variable, String)> = vec![];
+                for local in locals.iter().rev() {
+                    let shadowed = local.0 == var || captured.iter().any(|c| c.0 == local.0);
+                    if !shadowed && free.contains(local.0) {
+                        captured.push(local);
+                    }
+                }
+                let mut inner = vec![(var, "a0".to_string())];
+                for (k, (local, _)) in captured.iter().enumerate() {
+                    inner.push((local, format!("a{}", k + 1)));
+                }
+                let name = self.helper(body, &inner);
+                push_code(&mut code, &format!("{name}(z,c,"));
+                push_piece(&mut code, self.build(dag, e, locals));
+                for (_, local) in captured {
+                    push_code(&mut code, ",");
+                    push_code(&mut code, local);
+                }
+                push_code(&mut code, ")");
+            }
+            Expr::If(cond, then, otherwise) => {
+                push_code(&mut code, "(");
+                push_piece(&mut code, self.build(dag, cond, locals));
+                push_code(&mut code, "!=vec2(0.0)?");
+                push_piece(&mut code, self.build(dag, then, locals));
+                push_code(&mut code, ":");
+                push_piece(&mut code, self.build(dag, otherwise, locals));
+                push_code(&mut code, ")");
+            }
+            Expr::Call(def, args) => {
+                let name = match self.definitions.get(&Rc::as_ptr(def)) {
+                    Some(name) => name.clone(),
+                    None => {
+                        let params: Vec<_> = (def.params.iter().enumerate())
+                            .map(|(k, param)| (param, format!("a{k}")))
+                            .collect();
+                        let name = self.helper(&def.body, &params);
+                        self.definitions.insert(Rc::as_ptr(def), name.clone());
+                        name
+                    }
+                };
+                push_code(&mut code, &format!("{name}(z,c"));
+                for arg in args {
+                    push_code(&mut code, ",");
+                    push_piece(&mut code, self.build(dag, arg, locals));
+                }
+                push_code(&mut code, ")");
+            }
+        }
+        dag.intern(code)
+    }
+
+    /// Name of the helper returning `body` with `args` as its arguments, an
+    /// existing one if the same code was already generated.
+    fn helper<'e>(&mut self, body: &'e ExprComplex, args: &Locals<'e>) -> String {
+        let mut code = String::from("(vec2 z, vec2 c");
+        for (_, arg) in args {
+            code.push_str(&format!(", vec2 {arg}"));
         }
-        Expr::Operator(op, lhs, rhs) => {
-            let op_str = match op {
-                FieldOperator::Add => "add",
-                FieldOperator::Sub => "sub",
-                FieldOperator::Mul => "mul",
-                FieldOperator::Div => "div",
-            };
-            ret.push_str(op_str);
-            ret.push('(');
-            build_snippet(ret, lhs);
-            ret.push(',');
-            build_snippet(ret, rhs);
-            ret.push(')');
+        code.push_str(") {\n    ");
+        code.push_str(&self.function_body(body, args));
+        code.push_str("\n}\n");
+        let k = match self.helper_ids.get(&code) {
+            Some(&k) => k,
+            None => {
+                let k = self.helpers.len();
+                self.helpers.push(format!("vec2 helper_{k}{code}"));
+                self.helper_ids.insert(code, k);
+                k
+            }
+        };
+        format!("helper_{k}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fragment_source, glsl_float, Precision, Snippet};
+    use crate::expression::{call_chain, ExprComplex, Variable};
+
+    fn snippet(expr: &ExprComplex) -> String {
+        let mut snippet = Snippet::new(&[]);
+        snippet.function_body(expr, &[])
+    }
+
+    #[test]
+    fn simplified_snippet() {
+        let expr = "2*3*z+0".parse::<ExprComplex>().unwrap();
+        assert_eq!(snippet(&expr.simplify()), "return mul(vec2(6.0,0.0),z);");
+    }
+
+    #[test]
+    fn power_snippet() {
+        let expr = "z^5+z^-2+z^2.5+z^z".parse::<ExprComplex>().unwrap();
+        assert_eq!(
+            snippet(&expr.simplify()),
+            "return add(add(add(cpowi(z,5),cpowi(z,-2)),cpow(z,vec2(2.5,0.0))),cpow(z,z));"
+        );
+    }
+
+    #[test]
+    fn derivative_snippet() {
+        let expr = "exp(z)/z".parse::<ExprComplex>().unwrap();
+        assert_eq!(
+            snippet(&expr.derivative(Variable::Z).unwrap()),
+            "vec2 t0 = mul(cexp(z),vec2(1.0,0.0));\n    return div(sub(mul(t0,z),t0),mul(z,z));"
+        );
+    }
+
+    #[test]
+    fn common_subexpressions() {
+        let expr = "(z*z+1)/(z*z-1)".parse::<ExprComplex>().unwrap();
+        assert_eq!(
+            snippet(&expr),
+            "vec2 t0 = mul(z,z);\n    return div(add(t0,vec2(1.0,0.0)),sub(t0,vec2(1.0,0.0)));"
+        );
+        // `z*z` only counts the distinct `sin` and `cos` as its users
+        let expr = "sin(z*z)+cos(z*z)+sin(z*z)".parse::<ExprComplex>().unwrap();
+        assert_eq!(
+            snippet(&expr),
+            "vec2 t0 = mul(z,z);\n    vec2 t1 = csin(t0);\n    return add(add(t1,ccos(t0)),t1);"
+        );
+        let expr = "g(w) = w*w/(w*w+1); g(z)+g(z)*c"
+            .parse::<ExprComplex>()
+            .unwrap();
+        let src = fragment_source(&expr, &[], Precision::Medium);
+        assert!(src.contains(
+            "vec2 helper_0(vec2 z, vec2 c, vec2 a0) {\n    vec2 t0 = mul(a0,a0);\n    return div(t0,add(t0,vec2(1.0,0.0)));\n}\n"
+        ));
+        assert!(src.contains("vec2 t0 = helper_0(z,c,z);\n    return add(t0,mul(t0,c));\n}"));
+    }
+
+    #[test]
+    fn parameter_uniforms() {
+        let expr = "a*z*z+lambda*c".parse::<ExprComplex>().unwrap();
+        let parameters = [Variable::new("a"), Variable::new("lambda")];
+        let src = fragment_source(&expr, &parameters, Precision::Medium);
+        assert!(src.contains("uniform vec2 param_0;\nuniform vec2 param_1;\n"));
+        assert!(src.contains("add(mul(mul(param_0,z),z),mul(param_1,c))"));
+        assert!(!src.contains("/*BEGIN"));
+    }
+
+    #[test]
+    fn definition_helpers() {
+        let expr = "g(w) = w*w+1; f(z) = g(z)/g(z-1)"
+            .parse::<ExprComplex>()
+            .unwrap();
+        let src = fragment_source(&expr, &[], Precision::Medium);
+        assert!(src.contains(
+            "vec2 helper_0(vec2 z, vec2 c, vec2 a0) {\n    return add(mul(a0,a0),vec2(1.0,0.0));\n}\n"
+        ));
+        assert!(!src.contains("helper_1"));
+        assert!(src.contains("div(helper_0(z,c,z),helper_0(z,c,sub(z,vec2(1.0,0.0))))"));
+
+        // each definition is generated once
+        let program = call_chain(30, "w*w+c", |g| format!("{g}(w)/{g}(w+1)"), "z");
+        let expr = program.parse::<ExprComplex>().unwrap();
+        let mut snippet = Snippet::new(&[]);
+        assert_eq!(
+            snippet.function_body(&expr, &[]),
+            "return helper_29(z,c,z);"
+        );
+        assert_eq!(snippet.helpers.len(), 30);
+    }
+
+    #[test]
+    fn let_helpers() {
+        let expr = "a(w) = w; let u = z*z in let v = u+1 in u/v*a(v)"
+            .parse::<ExprComplex>()
+            .unwrap();
+        let src = fragment_source(&expr, &[], Precision::Medium);
+        // `a` first, then the inner let that captures `u` and calls `a`
+        assert!(src.contains("vec2 helper_0(vec2 z, vec2 c, vec2 a0) {\n    return a0;"));
+        assert!(src.contains(
+            "vec2 helper_1(vec2 z, vec2 c, vec2 a0, vec2 a1) {\n    return mul(div(a1,a0),helper_0(z,c,a0));"
+        ));
+        assert!(src.contains(
+            "vec2 helper_2(vec2 z, vec2 c, vec2 a0) {\n    return helper_1(z,c,add(a0,vec2(1.0,0.0)),a0);"
+        ));
+        assert!(src.contains("helper_2(z,c,mul(z,z))"));
+    }
+
+    #[test]
+    fn if_snippet() {
+        let expr = "if(z < 1, z, c)".parse::<ExprComplex>().unwrap();
+        let src = fragment_source(&expr, &[], Precision::Medium);
+        assert!(src.contains("(clt(z,vec2(1.0,0.0))!=vec2(0.0)?z:c)"));
+    }
+
+    #[test]
+    fn degree_define() {
+        let source = |input: &str| {
+            let expr = input.parse::<ExprComplex>().unwrap();
+            fragment_source(&expr, &[], Precision::Medium)
+        };
+        assert!(source("z^3+c").contains("#define DEGREE 3.0\n"));
+        assert!(source("exp(z)+c").contains("#define DEGREE 0.0\n"));
+    }
+
+    #[test]
+    fn palette_uniforms() {
+        let src = fragment_source(&ExprComplex::Variable(Variable::Z), &[], Precision::Medium);
+        let size = format!("#define PALETTE_SIZE {}\n", crate::palette::MAX_STOPS);
+        assert!(src.contains(&size));
+        assert!(src.contains("uniform vec4 palette[PALETTE_SIZE];"));
+    }
+
+    #[test]
+    fn float_literals() {
+        assert_eq!(glsl_float(1.0), "1.0");
+        assert_eq!(glsl_float(-3.0), "-3.0");
+        assert_eq!(glsl_float(0.1), "0.1");
+        assert_eq!(glsl_float(0.0), "0.0");
+        assert_eq!(glsl_float(-0.0), "-0.0");
+        assert_eq!(glsl_float(1e20), "1e20");
+        assert_eq!(glsl_float(1.5e-7), "1.5e-7");
+        assert_eq!(glsl_float(f32::MAX), "3.4028235e38");
+        assert_eq!(glsl_float(f32::MIN_POSITIVE), "1.1754944e-38");
+        assert_eq!(glsl_float(f32::NAN), "uintBitsToFloat(0x7fc00000u)");
+        assert_eq!(glsl_float(f32::INFINITY), "uintBitsToFloat(0x7f800000u)");
+        assert_eq!(
+            glsl_float(f32::NEG_INFINITY),
+            "uintBitsToFloat(0xff800000u)"
+        );
+        for x in [1.0, 1e20, 1.5e-7, 1e-45, 123456.79, -2.5e-12, f32::MAX] {
+            let literal = glsl_float(x);
+            assert!(literal.contains(['.', 'e']), "{literal}");
+            assert_eq!(literal.parse::<f32>().unwrap().to_bits(), x.to_bits());
         }
     }
+
+    #[test]
+    fn constant_snippet() {
+        let expr = "2+1e-9i".parse::<ExprComplex>().unwrap();
+        assert_eq!(snippet(&expr.simplify()), "return vec2(2.0,1e-9);");
+    }
+
+    #[test]
+    fn precision() {
+        let expr = "z".parse::<ExprComplex>().unwrap();
+        let src = fragment_source(&expr, &[], Precision::Medium);
+        assert!(src.contains("\nprecision mediump float;\n"));
+        let src = fragment_source(&expr, &[], Precision::High);
+        assert!(src.contains("\nprecision highp float;\n"));
+        assert!(!src.contains("mediump"));
+    }
 }
@@ -0,0 +1,727 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter, Result},
+    rc::Rc,
+};
+
+use crate::{
+    algebra::{Complex, Real},
+    expression::{
+        fresh_name, ComplexFunction, ComplexOperator, Definition, Expr, FieldFunction,
+        FieldOperator, Variable,
+    },
+};
+
+/// Binding strength of a printed expression, following the `expr`,
+/// `comparison`, `add`, `mul`, `unary`, `power` and `term` productions of the
+/// parser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Precedence {
+    /// `let`, whose body extends as far right as possible.
+    Let,
+    /// Comparisons, which do not chain.
+    Comparison,
+    Sum,
+    Product,
+    Unary,
+    Power,
+    Term,
+}
+
+/// Spelling of a function or operator in the expression language.
+pub trait Symbol {
+    fn symbol(&self) -> &'static str;
+    /// `Term` for functions and operators written in call syntax.
+    fn precedence(&self) -> Precedence {
+        Precedence::Term
+    }
+}
+
+/// Constants that can be written in the expression language, in a form that
+/// the parser reads back as the same single constant, e.g. `-2`, `1.5-2i` or
+/// `inf`. The precedence is that of the expression the text would otherwise
+/// be.
+pub trait Literal {
+    fn precedence(&self) -> Precedence;
+    fn fmt_literal(&self, f: &mut Formatter<'_>) -> Result;
+    /// Whether `self`, written before `+` or `-` and the literal of `im`,
+    /// would be read as a single constant with it, like `1.5-2i`.
+    fn joins(&self, _im: &Self) -> bool {
+        false
+    }
+}
+
+impl Symbol for FieldFunction {
+    fn symbol(&self) -> &'static str {
+        match self {
+            FieldFunction::Neg => "-",
+            FieldFunction::Inv => "inv",
+        }
+    }
+    fn precedence(&self) -> Precedence {
+        match self {
+            FieldFunction::Neg => Precedence::Unary,
+            FieldFunction::Inv => Precedence::Term,
+        }
+    }
+}
+
+impl Symbol for FieldOperator {
+    fn symbol(&self) -> &'static str {
+        match self {
+            FieldOperator::Add => "+",
+            FieldOperator::Sub => "-",
+            FieldOperator::Mul => "*",
+            FieldOperator::Div => "/",
+        }
+    }
+    fn precedence(&self) -> Precedence {
+        match self {
+            FieldOperator::Add | FieldOperator::Sub => Precedence::Sum,
+            FieldOperator::Mul | FieldOperator::Div => Precedence::Product,
+        }
+    }
+}
+
+impl Symbol for ComplexFunction {
+    fn symbol(&self) -> &'static str {
+        match self {
+            ComplexFunction::Field(fun) => fun.symbol(),
+            ComplexFunction::Re => "Re",
+            ComplexFunction::Im => "Im",
+            ComplexFunction::Abs => "abs",
+            ComplexFunction::Exp => "exp",
+            ComplexFunction::Log => "log",
+            ComplexFunction::Sqrt => "sqrt",
+            ComplexFunction::Sin => "sin",
+            ComplexFunction::Cos => "cos",
+            ComplexFunction::Tan => "tan",
+            ComplexFunction::Sinh => "sinh",
+            ComplexFunction::Cosh => "cosh",
+            ComplexFunction::Tanh => "tanh",
+            ComplexFunction::Asin => "asin",
+            ComplexFunction::Atan => "atan",
+        }
+    }
+    fn precedence(&self) -> Precedence {
+        match self {
+            ComplexFunction::Field(fun) => fun.precedence(),
+            _ => Precedence::Term,
+        }
+    }
+}
+
+impl Symbol for ComplexOperator {
+    fn symbol(&self) -> &'static str {
+        match self {
+            ComplexOperator::Field(op) => op.symbol(),
+            ComplexOperator::Pow => "^",
+            ComplexOperator::Less => "<",
+            ComplexOperator::LessEq => "<=",
+            ComplexOperator::Greater => ">",
+            ComplexOperator::GreaterEq => ">=",
+        }
+    }
+    fn precedence(&self) -> Precedence {
+        match self {
+            ComplexOperator::Field(op) => op.precedence(),
+            ComplexOperator::Pow => Precedence::Power,
+            ComplexOperator::Less
+            | ComplexOperator::LessEq
+            | ComplexOperator::Greater
+            | ComplexOperator::GreaterEq => Precedence::Comparison,
+        }
+    }
+}
+
+macro_rules! impl_literal {
+    ($t:ty) => {
+        /// In the shortest decimal form that reads back exactly, with a
+        /// leading `-` if negative, or as `inf`, `-inf` or `nan`.
+        impl Literal for $t {
+            fn precedence(&self) -> Precedence {
+                match self.is_sign_negative() && !self.is_nan() {
+                    true => Precedence::Unary,
+                    false => Precedence::Term,
+                }
+            }
+            fn fmt_literal(&self, f: &mut Formatter<'_>) -> Result {
+                match self {
+                    x if x.is_nan() => write!(f, "nan"),
+                    x if x.is_infinite() => write!(f, "{}inf", if *x < 0.0 { "-" } else { "" }),
+                    x => write!(f, "{x}"),
+                }
+            }
+        }
+    };
+}
+
+impl_literal!(f32);
+impl_literal!(f64);
+
+impl<T> Literal for Complex<T>
+where
+    T: Real + Literal,
+{
+    fn precedence(&self) -> Precedence {
+        if self.im == T::O {
+            self.re.precedence()
+        } else if *self == Complex::I {
+            Precedence::Term
+        } else if self.re == T::O {
+            // `2i` is a literal, `-2i` a negative one
+            self.im.precedence()
+        } else {
+            Precedence::Sum
+        }
+    }
+
+    fn fmt_literal(&self, f: &mut Formatter<'_>) -> Result {
+        if self.im == T::O {
+            return self.re.fmt_literal(f);
+        }
+        if *self == Complex::I {
+            return write!(f, "i");
+        }
+        if self.re != T::O {
+            self.re.fmt_literal(f)?;
+            write!(f, "{}", if self.im < T::O { "-" } else { "+" })?;
+            self.im.abs().fmt_literal(f)?;
+        } else {
+            self.im.fmt_literal(f)?;
+        }
+        write!(f, "i")
+    }
+
+    fn joins(&self, im: &Self) -> bool {
+        self.im == T::O && im.re == T::O && im.im != T::O
+    }
+}
+
+impl Display for Variable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_str(self.name())
+    }
+}
+
+impl<T, F, O> Expr<T, F, O>
+where
+    T: Literal,
+    F: Symbol,
+    O: Symbol,
+{
+    pub fn precedence(&self) -> Precedence {
+        match self {
+            Expr::Variable(_) => Precedence::Term,
+            Expr::Constant(ct) => ct.precedence(),
+            Expr::Function(fun, _) => fun.precedence(),
+            Expr::Operator(op, _, _) => op.precedence(),
+            Expr::Let(..) => Precedence::Let,
+            Expr::Call(..) | Expr::If(..) => Precedence::Term,
+        }
+    }
+
+    /// Writes `self`, in parentheses if it binds weaker than `min`.
+    fn fmt_at(&self, f: &mut Formatter<'_>, min: Precedence, names: &Names<T, F, O>) -> Result {
+        let e = InProgram(self, names);
+        if self.precedence() < min {
+            write!(f, "({e})")
+        } else {
+            write!(f, "{e}")
+        }
+    }
+
+    /// Writes `self` without its definitions, calls using the names in
+    /// `names`.
+    fn fmt_in(&self, f: &mut Formatter<'_>, names: &Names<T, F, O>) -> Result {
+        let bare = |e| InProgram(e, names);
+        match self {
+            Expr::Variable(var) => write!(f, "{var}"),
+            Expr::Constant(ct) => ct.fmt_literal(f),
+            Expr::Function(fun, e) => match fun.precedence() {
+                Precedence::Term => write!(f, "{}({})", fun.symbol(), bare(e)),
+                // `-2` would read back as the constant
+                Precedence::Unary if matches!(**e, Expr::Constant(_)) => {
+                    write!(f, "{}({})", fun.symbol(), bare(e))
+                }
+                precedence => {
+                    write!(f, "{}", fun.symbol())?;
+                    e.fmt_at(f, precedence, names)
+                }
+            },
+            Expr::Operator(op, left, right) => {
+                let (left_min, right_min) = match op.precedence() {
+                    Precedence::Term => {
+                        return write!(f, "{}({},{})", op.symbol(), bare(left), bare(right));
+                    }
+                    Precedence::Let | Precedence::Comparison => (Precedence::Sum, Precedence::Sum),
+                    Precedence::Sum => (Precedence::Sum, Precedence::Product),
+                    Precedence::Product => (Precedence::Product, Precedence::Unary),
+                    Precedence::Unary | Precedence::Power => (Precedence::Term, Precedence::Unary),
+                };
+                left.fmt_at(f, left_min, names)?;
+                write!(f, "{}", op.symbol())?;
+                match (&**left, &**right) {
+                    // `1+2i` would read back as the constant
+                    (Expr::Constant(l), Expr::Constant(r))
+                        if op.precedence() == Precedence::Sum && l.joins(r) =>
+                    {
+                        write!(f, "({})", bare(right))
+                    }
+                    _ => right.fmt_at(f, right_min, names),
+                }
+            }
+            Expr::Let(var, e, body) => {
+                write!(f, "let {var} = ")?;
+                e.fmt_at(f, Precedence::Sum, names)?;
+                write!(f, " in {}", bare(body))
+            }
+            Expr::Call(def, args) => {
+                let name = names.get(&Rc::as_ptr(def)).unwrap_or(&def.name);
+                write!(f, "{name}(")?;
+                fmt_list(f, args.iter().map(bare))?;
+                write!(f, ")")
+            }
+            Expr::If(cond, then, otherwise) => {
+                write!(f, "if({},{},{})", bare(cond), bare(then), bare(otherwise))
+            }
+        }
+    }
+}
+
+/// Printed names of the definitions of a program, by address.
+type Names<T, F, O> = HashMap<*const Definition<T, F, O>, String>;
+
+/// An expression printed with the definition names of its program.
+struct InProgram<'a, T, F, O>(&'a Expr<T, F, O>, &'a Names<T, F, O>);
+
+impl<T, F, O> Display for InProgram<'_, T, F, O>
+where
+    T: Literal,
+    F: Symbol,
+    O: Symbol,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.0.fmt_in(f, self.1)
+    }
+}
+
+/// Infix notation with the fewest parentheses the grammar needs to read the
+/// same tree back: `+`, `-`, `*` and `/` are left associative, so a right
+/// operand of the same precedence is parenthesised, while `^` is right
+/// associative and its base is parenthesised instead.
+///
+/// The definitions called are written first, as a program:
+/// `g(w) = w*w+1; g(z)/g(z-1)`.
+impl<T, F, O> Display for Expr<T, F, O>
+where
+    T: Literal,
+    F: Symbol,
+    O: Symbol,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let mut names = Names::new();
+        for def in program(self, &mut names) {
+            fmt_definition(f, def, &names)?;
+            write!(f, "; ")?;
+        }
+        self.fmt_in(f, &names)
+    }
+}
+
+/// The definitions of the program printing `expr`, in order, with their
+/// names put in `names`. Each comes after those it calls, and before those
+/// named like a variable of its body, which would otherwise read back as
+/// calls. A name already given to another definition, which the parser would
+/// shadow, gets a suffix.
+fn program<'a, T, F, O>(
+    expr: &'a Expr<T, F, O>,
+    names: &mut Names<T, F, O>,
+) -> Vec<&'a Definition<T, F, O>> {
+    let defs = expr.definitions();
+    let index: HashMap<_, _> = (defs.iter().enumerate())
+        .map(|(k, def)| (Rc::as_ptr(def), k))
+        .collect();
+    let mut used: HashSet<&str> = HashSet::new();
+    let mut deps = vec![vec![]; defs.len()];
+    for (k, def) in defs.iter().enumerate() {
+        let (mut calls, mut vars) = (vec![], HashSet::new());
+        uses(&def.body, &mut calls, &mut vars);
+        deps[k].extend(calls.iter().map(|callee| index[&Rc::as_ptr(callee)]));
+        for (j, other) in defs.iter().enumerate() {
+            if j != k && vars.contains(other.name.as_str()) {
+                deps[j].push(k);
+            }
+        }
+        used.extend(vars);
+        used.extend(def.params.iter().map(Variable::name));
+    }
+    let (mut calls, mut vars) = (vec![], HashSet::new());
+    uses(expr, &mut calls, &mut vars);
+    used.extend(vars);
+
+    fn visit(k: usize, deps: &[Vec<usize>], seen: &mut [bool], order: &mut Vec<usize>) {
+        if !seen[k] {
+            seen[k] = true;
+            for &dep in &deps[k] {
+                visit(dep, deps, seen, order);
+            }
+            order.push(k);
+        }
+    }
+    let (mut seen, mut order) = (vec![false; defs.len()], vec![]);
+    for k in 0..defs.len() {
+        visit(k, &deps, &mut seen, &mut order);
+    }
+
+    let mut taken = HashSet::new();
+    for &k in &order {
+        let name = fresh_name(&defs[k].name, |name| {
+            taken.contains(name) || (name != defs[k].name && used.contains(name))
+        });
+        taken.insert(name.clone());
+        names.insert(Rc::as_ptr(defs[k]), name);
+    }
+    order.into_iter().map(|k| &**defs[k]).collect()
+}
+
+/// The calls and variables of `expr`, not counting those in the bodies of
+/// the definitions it calls.
+fn uses<'a, T, F, O>(
+    expr: &'a Expr<T, F, O>,
+    calls: &mut Vec<&'a Rc<Definition<T, F, O>>>,
+    vars: &mut HashSet<&'a str>,
+) {
+    match expr {
+        Expr::Variable(var) => {
+            vars.insert(var.name());
+        }
+        Expr::Constant(_) => {}
+        Expr::Function(_, e) => uses(e, calls, vars),
+        Expr::Operator(_, left, right) => {
+            uses(left, calls, vars);
+            uses(right, calls, vars);
+        }
+        Expr::Let(var, e, body) => {
+            vars.insert(var.name());
+            uses(e, calls, vars);
+            uses(body, calls, vars);
+        }
+        Expr::Call(def, args) => {
+            calls.push(def);
+            for arg in args {
+                uses(arg, calls, vars);
+            }
+        }
+        Expr::If(cond, then, otherwise) => {
+            uses(cond, calls, vars);
+            uses(then, calls, vars);
+            uses(otherwise, calls, vars);
+        }
+    }
+}
+
+fn fmt_definition<T, F, O>(
+    f: &mut Formatter<'_>,
+    def: &Definition<T, F, O>,
+    names: &Names<T, F, O>,
+) -> Result
+where
+    T: Literal,
+    F: Symbol,
+    O: Symbol,
+{
+    let name = names.get(&(def as *const _)).unwrap_or(&def.name);
+    write!(f, "{name}(")?;
+    fmt_list(f, &def.params)?;
+    write!(f, ") = {}", InProgram(&def.body, names))
+}
+
+/// As written in a program, `g(w) = w*w+1`, without the definitions it
+/// calls.
+impl<T, F, O> Display for Definition<T, F, O>
+where
+    T: Literal,
+    F: Symbol,
+    O: Symbol,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        fmt_definition(f, self, &Names::new())
+    }
+}
+
+fn fmt_list(f: &mut Formatter<'_>, items: impl IntoIterator<Item = impl Display>) -> Result {
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            write!(f, ",")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fmt::Debug, rc::Rc, str::FromStr};
+
+    use proptest::prelude::*;
+
+    use crate::{
+        algebra::Complex,
+        expression::{
+            ComplexFunction, ComplexOperator, Definition, Expr, ExprComplex, FieldFunction,
+            FieldOperator, Variable,
+        },
+    };
+
+    type ExprReal = Expr<f32, FieldFunction, FieldOperator>;
+
+    fn round_trip(input: &str) -> String {
+        input.parse::<ExprComplex>().unwrap().to_string()
+    }
+
+    #[test]
+    fn minimal_parens() {
+        assert_eq!(round_trip("((z*z)+(c))"), "z*z+c");
+        assert_eq!(round_trip("(z+1)/(z-1)"), "(z+1)/(z-1)");
+        assert_eq!(round_trip("z-(z-1)"), "z-(z-1)");
+        assert_eq!(round_trip("z/(z*2)"), "z/(z*2)");
+        assert_eq!(round_trip("(z-1)-z"), "z-1-z");
+        assert_eq!(round_trip("-(z*z)"), "-(z*z)");
+        assert_eq!(round_trip("exp(1/z)"), "exp(1/z)");
+        assert_eq!(round_trip("pow(z+1,2)*i"), "(z+1)^2*i");
+        assert_eq!(round_trip("-z^2"), "-z^2");
+        assert_eq!(round_trip("(-z)^2"), "(-z)^2");
+        assert_eq!(round_trip("z^(2^z)"), "z^2^z");
+        assert_eq!(round_trip("(z^2)^z"), "(z^2)^z");
+        assert_eq!(round_trip("z^(-1)*(-z)"), "z^-1*-z");
+        assert_eq!(round_trip("exp(z)^2"), "exp(z)^2");
+    }
+
+    #[test]
+    fn constants() {
+        let expr: ExprComplex = Expr::operator(
+            FieldOperator::Mul,
+            Expr::Variable(Variable::Z),
+            Expr::Constant(Complex { re: 1.5, im: -2.0 }),
+        );
+        assert_eq!(expr.to_string(), "z*(1.5-2i)");
+        let expr: ExprComplex = Expr::operator(
+            FieldOperator::Div,
+            Expr::Constant(Complex { re: 0.0, im: 0.5 }),
+            Expr::Variable(Variable::Z),
+        );
+        assert_eq!(expr.to_string(), "0.5i/z");
+        let expr: ExprComplex = Expr::operator(
+            ComplexOperator::Pow,
+            Expr::Constant(Complex::from(-2.0)),
+            Expr::Constant(Complex::from(-2.0)),
+        );
+        assert_eq!(expr.to_string(), "(-2)^-2");
+        let expr: ExprComplex = Expr::operator(
+            ComplexOperator::Pow,
+            Expr::Constant(Complex { re: 0.0, im: -2.0 }),
+            Expr::Constant(Complex { re: 0.0, im: 2.0 }),
+        );
+        assert_eq!(expr.to_string(), "(-2i)^2i");
+    }
+
+    /// The definitions of `program`, shared by all the calls generated.
+    fn definitions<T, F, O>(program: &str) -> Vec<Rc<Definition<T, F, O>>>
+    where
+        Expr<T, F, O>: FromStr<Err: Debug>,
+    {
+        let program = program.parse::<Expr<T, F, O>>().unwrap();
+        program.definitions().into_iter().cloned().collect()
+    }
+
+    /// Lets, calls of `defs` and ifs over `inner`.
+    fn compound<T, F, O>(
+        inner: BoxedStrategy<Expr<T, F, O>>,
+        defs: Vec<Rc<Definition<T, F, O>>>,
+    ) -> impl Strategy<Value = Expr<T, F, O>>
+    where
+        T: Debug + Clone + 'static,
+        F: Debug + Clone + 'static,
+        O: Debug + Clone + 'static,
+    {
+        prop_oneof![
+            (inner.clone(), inner.clone()).prop_map(|(e, body)| Expr::let_in(
+                Variable::new("w"),
+                e,
+                body
+            )),
+            (0..defs.len(), inner.clone(), inner.clone()).prop_map(move |(k, a, b)| {
+                let def = defs[k].clone();
+                let args = [a, b].into_iter().take(def.params.len()).collect();
+                Expr::Call(def, args)
+            }),
+            (inner.clone(), inner.clone(), inner).prop_map(|(c, a, b)| Expr::if_else(c, a, b)),
+        ]
+    }
+
+    /// Any `f32` but NaN, which is not equal to itself.
+    fn number() -> impl Strategy<Value = f32> {
+        use prop::num::f32::*;
+        POSITIVE | NEGATIVE | NORMAL | SUBNORMAL | ZERO | INFINITE
+    }
+
+    fn real_expr() -> impl Strategy<Value = ExprReal> {
+        let leaf = prop_oneof![
+            Just(Expr::Variable(Variable::Z)),
+            Just(Expr::Variable(Variable::C)),
+            Just(Expr::Variable(Variable::new("w"))),
+            number().prop_map(Expr::Constant),
+        ];
+        let defs = definitions("g(w) = w*w+c; h(a, b) = g(a)-b*z; h(z, c)");
+        leaf.prop_recursive(6, 64, 3, move |inner| {
+            prop_oneof![
+                compound(inner.clone(), defs.clone()),
+                (
+                    prop_oneof![Just(FieldFunction::Neg), Just(FieldFunction::Inv)],
+                    inner.clone()
+                )
+                    .prop_map(|(fun, e)| Expr::function(fun, e)),
+                (
+                    prop_oneof![
+                        Just(FieldOperator::Add),
+                        Just(FieldOperator::Sub),
+                        Just(FieldOperator::Mul),
+                        Just(FieldOperator::Div),
+                    ],
+                    inner.clone(),
+                    inner
+                )
+                    .prop_map(|(op, l, r)| Expr::operator(op, l, r)),
+            ]
+        })
+    }
+
+    fn complex_expr() -> impl Strategy<Value = ExprComplex> {
+        let leaf = prop_oneof![
+            Just(Expr::Variable(Variable::Z)),
+            Just(Expr::Variable(Variable::C)),
+            Just(Expr::Variable(Variable::new("w"))),
+            Just(Expr::Constant(Complex::I)),
+            number().prop_map(|re| Expr::Constant(Complex::from(re))),
+            number().prop_map(|im| Expr::Constant(Complex { re: 0.0, im })),
+            (number(), number()).prop_map(|(re, im)| Expr::Constant(Complex { re, im })),
+        ];
+        let function = prop_oneof![
+            Just(ComplexFunction::Field(FieldFunction::Neg)),
+            Just(ComplexFunction::Field(FieldFunction::Inv)),
+            Just(ComplexFunction::Re),
+            Just(ComplexFunction::Im),
+            Just(ComplexFunction::Abs),
+            Just(ComplexFunction::Exp),
+            Just(ComplexFunction::Log),
+            Just(ComplexFunction::Sqrt),
+            Just(ComplexFunction::Sin),
+            Just(ComplexFunction::Cos),
+            Just(ComplexFunction::Tan),
+            Just(ComplexFunction::Sinh),
+            Just(ComplexFunction::Cosh),
+            Just(ComplexFunction::Tanh),
+            Just(ComplexFunction::Asin),
+            Just(ComplexFunction::Atan),
+        ];
+        let operator = prop_oneof![
+            Just(ComplexOperator::Field(FieldOperator::Add)),
+            Just(ComplexOperator::Field(FieldOperator::Sub)),
+            Just(ComplexOperator::Field(FieldOperator::Mul)),
+            Just(ComplexOperator::Field(FieldOperator::Div)),
+            Just(ComplexOperator::Pow),
+            Just(ComplexOperator::Less),
+            Just(ComplexOperator::GreaterEq),
+        ];
+        let defs =
+            definitions("g(w) = w^2+c; h(a, b) = if(a < b, g(a), let v = b in v*z); h(z, c)");
+        leaf.prop_recursive(6, 64, 3, move |inner| {
+            prop_oneof![
+                compound(inner.clone(), defs.clone()),
+                (function.clone(), inner.clone()).prop_map(|(fun, e)| Expr::function(fun, e)),
+                (operator.clone(), inner.clone(), inner)
+                    .prop_map(|(op, l, r)| Expr::operator(op, l, r)),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn real_round_trip(expr in real_expr()) {
+            prop_assert_eq!(expr.to_string().parse::<ExprReal>(), Ok(expr));
+        }
+
+        #[test]
+        fn complex_round_trip(expr in complex_expr()) {
+            prop_assert_eq!(expr.to_string().parse::<ExprComplex>(), Ok(expr));
+        }
+    }
+
+    #[test]
+    fn non_finite() {
+        assert_eq!(round_trip("-inf*z"), "-inf*z");
+        assert_eq!(round_trip("z-(-infi)"), "z--infi");
+        assert_eq!(round_trip("nan+nani"), "nan+nani");
+        assert_eq!(round_trip("1-(nani)"), "1-(nani)");
+        let nan: ExprComplex = Expr::operator(
+            FieldOperator::Mul,
+            Expr::Constant(Complex {
+                re: 1.0,
+                im: f32::NAN,
+            }),
+            Expr::Variable(Variable::Z),
+        );
+        assert_eq!(nan.to_string(), "(1+nani)*z");
+        assert_eq!(round_trip(&nan.to_string()), "(1+nani)*z");
+    }
+
+    #[test]
+    fn let_and_call() {
+        assert_eq!(round_trip("let w = z*z in w+1"), "let w = z*z in w+1");
+        assert_eq!(round_trip("2*(let w = z in w)"), "2*(let w = z in w)");
+        assert_eq!(
+            round_trip("let w = (let v = z in v) in w"),
+            "let w = (let v = z in v) in w"
+        );
+        assert_eq!(
+            round_trip("g(a, b) = a*b+1; g(z, c)"),
+            "g(a,b) = a*b+1; g(z,c)"
+        );
+        assert_eq!(
+            round_trip("g(w) = w*w+1; g(z)/g(z-1)"),
+            "g(w) = w*w+1; g(z)/g(z-1)"
+        );
+        // callees first, each once
+        assert_eq!(
+            round_trip("f(w) = w+1; g(w) = f(w)*f(c); h(w) = w; g(h(z))+f(z)"),
+            "h(w) = w; f(w) = w+1; g(w) = f(w)*f(c); g(h(z))+f(z)"
+        );
+        // a redefinition is renamed rather than shadowing the first
+        assert_eq!(
+            round_trip("g(w) = w; h(w) = g(w); g(w) = 2*w; g(z)+h(z)"),
+            "g(w) = 2*w; g_2(w) = w; h(w) = g_2(w); g(z)+h(z)"
+        );
+        // `f` is a variable in the body of `g`, so `g` comes first
+        assert_eq!(
+            round_trip("g(w) = f*w; f(w) = w; f(z)+g(z)"),
+            "g(w) = f*w; f(w) = w; f(z)+g(z)"
+        );
+        let expr = "g(a, b) = a*b+1; g(z, c)".parse::<ExprComplex>().unwrap();
+        let Expr::Call(def, _) = expr else {
+            panic!("not a call: {expr:?}")
+        };
+        assert_eq!(def.to_string(), "g(a,b) = a*b+1");
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(round_trip("z+1 < 2*z"), "z+1<2*z");
+        assert_eq!(round_trip("(z < 1) + (z >= c)"), "(z<1)+(z>=c)");
+        assert_eq!(round_trip("(z < 1) > 0"), "(z<1)>0");
+        assert_eq!(
+            round_trip("if(z <= 1, z, let w = z in w)"),
+            "if(z<=1,z,let w = z in w)"
+        );
+    }
+}
@@ -0,0 +1,453 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    algebra::{Complex, Zero},
+    bytecode::Program,
+    expression::{ComplexFunction, ComplexOperator, ExprComplex, Variable},
+    palette::Palette,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "webgl")]
+use wasm_bindgen::prelude::*;
+
+#[cfg_attr(feature = "webgl", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DrawMode {
+    DomainColouring = 1,
+    ParameterStability = 2,
+    Julia = 3,
+}
+
+pub const SUBSAMPLE_ROOT: i32 = 2; // subsample 4 points per pixel
+
+/// Orbits escape once `|z|` reaches it, as `ESCAPE_RADIUS` in `plotter.frag`.
+pub const ESCAPE_RADIUS: f32 = 10.0;
+
+/// The part of the plane drawn and the size of the image, as the `center`,
+/// `scale` and `resolution` uniforms of `plotter.frag`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Viewport {
+    /// The point at the middle of the image. The `center` uniform is the
+    /// offset [`center_uniform`] of it instead.
+    pub center: Complex<f32>,
+    /// Half the width of the view in the plane, the height follows from the
+    /// aspect ratio.
+    pub xscale: f32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The `center` uniform of `plotter.frag` that puts `center` at the middle of
+/// the image, and the other way around: the uniform is subtracted from the
+/// point before the imaginary axis is flipped.
+pub fn center_uniform(center: Complex<f32>) -> Complex<f32> {
+    Complex {
+        re: -center.re,
+        im: center.im,
+    }
+}
+
+impl Viewport {
+    fn scale(&self) -> (f32, f32) {
+        let yscale = self.height as f32 / self.width as f32 * self.xscale;
+        (self.xscale, yscale)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenderError {
+    /// Fewer than 1 iterations, which leaves no escape time to colour.
+    MaxIter(i32),
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderError::MaxIter(max_iter) => {
+                write!(f, "{max_iter} iterations, expected at least 1")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "webgl")]
+impl From<RenderError> for JsValue {
+    fn from(err: RenderError) -> Self {
+        err.to_string().into()
+    }
+}
+
+/// `Ok` if the escape-time modes can be drawn with `max_iter` iterations.
+pub fn check_max_iter(max_iter: i32) -> Result<(), RenderError> {
+    match max_iter {
+        1.. => Ok(()),
+        _ => Err(RenderError::MaxIter(max_iter)),
+    }
+}
+
+/// Draws `function` on the CPU as `plotter.frag` does on the GPU, into
+/// RGBA8 pixels row by row from the top left, alpha always opaque.
+///
+/// `parameters` holds the values of the free variables other than `z`, by
+/// name, unset ones are 0. `c` is only read in [`DrawMode::Julia`], the
+/// other modes set it themselves. `palette` colours the escape-time modes.
+pub fn render(
+    function: &ExprComplex,
+    draw_mode: DrawMode,
+    viewport: &Viewport,
+    max_iter: i32,
+    parameters: &HashMap<String, Complex<f32>>,
+    palette: &Palette,
+) -> Result<Vec<u8>, RenderError> {
+    let rows = 0..viewport.height;
+    render_rows(
+        function, draw_mode, viewport, max_iter, parameters, palette, rows,
+    )
+}
+
+/// The given rows of [`render`], to draw a large image in bands.
+pub fn render_rows(
+    function: &ExprComplex,
+    draw_mode: DrawMode,
+    viewport: &Viewport,
+    max_iter: i32,
+    parameters: &HashMap<String, Complex<f32>>,
+    palette: &Palette,
+    rows: Range<u32>,
+) -> Result<Vec<u8>, RenderError> {
+    check_max_iter(max_iter)?;
+    let mut variables = vec![Variable::Z, Variable::C];
+    variables.extend(
+        (function.free_variables().into_iter())
+            .filter(|&var| *var != Variable::Z && *var != Variable::C)
+            .cloned(),
+    );
+    let mut values: Vec<_> = (variables.iter())
+        .map(|var| *parameters.get(var.name()).unwrap_or(&Complex::O))
+        .collect();
+    let program = function
+        .compile(&variables)
+        .expect("every free variable has a slot");
+    let mut shader = Shader {
+        degree: function.degree(&Variable::Z),
+        program,
+        values: &mut values,
+        stack: vec![],
+        draw_mode,
+        max_iter,
+        palette,
+    };
+
+    let (width, height) = (viewport.width as usize, viewport.height as usize);
+    let (xscale, yscale) = viewport.scale();
+    let n = SUBSAMPLE_ROOT;
+    let mut pixels = Vec::with_capacity(4 * width * rows.len());
+    for row in rows.map(|row| row as usize) {
+        for col in 0..width {
+            // `st` of the pixel center, `y` pointing up
+            let st = (
+                (2 * col + 1) as f32 / width as f32 - 1.0,
+                1.0 - (2 * row + 1) as f32 / height as f32,
+            );
+            let mut rgb = [0.0; 3];
+            for i in 0..n {
+                for j in 0..n {
+                    let x = st.0 + subsample(i, n) / (2.0 * width as f32);
+                    let y = st.1 + subsample(j, n) / (2.0 * height as f32);
+                    let z = Complex {
+                        re: viewport.center.re + x * xscale,
+                        im: viewport.center.im - y * yscale,
+                    };
+                    let color = shader.color(z);
+                    for k in 0..3 {
+                        rgb[k] += color[k];
+                    }
+                }
+            }
+            for channel in rgb {
+                let average = channel / (n * n) as f32;
+                pixels.push((average.clamp(0.0, 1.0) * 255.0).round() as u8);
+            }
+            pixels.push(255);
+        }
+    }
+    Ok(pixels)
+}
+
+/// `i`-th of `n` equidistant offsets in `(-1, 1)`, as `subsample` in
+/// `plotter.frag`.
+fn subsample(i: i32, n: i32) -> f32 {
+    (2 * i - (n - 1)) as f32 / n as f32
+}
+
+/// The state of the fragment shader.
+struct Shader<'a> {
+    program: Program<Complex<f32>, ComplexFunction, ComplexOperator>,
+    /// Values of `z`, `c` and the parameters, in the slots of `program`.
+    values: &'a mut [Complex<f32>],
+    stack: Vec<Complex<f32>>,
+    draw_mode: DrawMode,
+    max_iter: i32,
+    /// Degree of the function in `z`, see [`smoothing`].
+    degree: Option<u32>,
+    palette: &'a Palette,
+}
+
+impl Shader<'_> {
+    fn f(&mut self, z: Complex<f32>, c: Complex<f32>) -> Complex<f32> {
+        self.values[0] = z;
+        self.values[1] = c;
+        self.program.eval_with(self.values, &mut self.stack)
+    }
+
+    fn color(&mut self, z: Complex<f32>) -> [f32; 3] {
+        match self.draw_mode {
+            DrawMode::DomainColouring => complex2rgb(self.f(z, Complex::O)),
+            DrawMode::ParameterStability => self.iter_color(Complex::O, z),
+            DrawMode::Julia => self.iter_color(z, self.values[1]),
+        }
+    }
+
+    /// `iter_color` and `julia_color`: the escape time of the orbit of `z`.
+    fn iter_color(&mut self, mut z: Complex<f32>, c: Complex<f32>) -> [f32; 3] {
+        let mut i = 0;
+        while length(z) < ESCAPE_RADIUS && {
+            i += 1;
+            i < self.max_iter
+        } {
+            z = self.f(z, c);
+        }
+        if i == self.max_iter {
+            return [0.0; 3];
+        }
+        let smoothed = i as f32 - smoothing(z, self.degree);
+        self.palette.color(smoothed, self.max_iter)
+    }
+}
+
+/// Fraction of an iteration to take off the escape time of an orbit that
+/// escaped at `z`, so that it varies continuously instead of in bands: `0`
+/// at `|z| = R` and `1` at `R^d`, where `R` is [`ESCAPE_RADIUS`] and `d` the
+/// degree of the function in `z`, as `smoothing` in `plotter.frag`. `0` if
+/// the function is not a polynomial of degree 2 or more, which has no such
+/// formula.
+pub fn smoothing(z: Complex<f32>, degree: Option<u32>) -> f32 {
+    match degree {
+        Some(d) if d > 1 => (length(z).ln() / ESCAPE_RADIUS.ln()).ln() / (d as f32).ln(),
+        _ => 0.0,
+    }
+}
+
+fn length(z: Complex<f32>) -> f32 {
+    z.norm_sq().sqrt()
+}
+
+const E_SQRT: f32 = 1.64872;
+const TAU: f32 = std::f32::consts::TAU;
+
+/// Hue from the argument, lightness from the modulus, with bands at powers of
+/// `e` once past `sqrt(e)`.
+fn complex2rgb(z: Complex<f32>) -> [f32; 3] {
+    let r = length(z);
+    let h = z.im.atan2(z.re) / TAU;
+    let l = match r > E_SQRT {
+        true => 1.0 - 0.5 * (2.0 * (r.ln() - (r.ln() + 0.5).floor())).abs().sqrt(),
+        false => 0.5 * r / E_SQRT,
+    };
+    // hsl2rgb, with GLSL's `mod`
+    [0.0, 4.0, 2.0].map(|offset| {
+        let x = h * 6.0 + offset;
+        let rgb = ((x - 6.0 * (x / 6.0).floor() - 3.0).abs() - 1.0).clamp(0.0, 1.0);
+        l + (rgb - 0.5) * (1.0 - (2.0 * l - 1.0).abs())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{center_uniform, complex2rgb, render, smoothing, DrawMode, RenderError, Viewport};
+    use crate::{algebra::Complex, expression::ExprComplex, palette::Palette};
+
+    fn render_str(input: &str, draw_mode: DrawMode, size: u32, max_iter: i32) -> Vec<u8> {
+        let viewport = Viewport {
+            center: Complex::from(0.0),
+            xscale: 2.0,
+            width: size,
+            height: size,
+        };
+        let function = input.parse::<ExprComplex>().unwrap();
+        render(
+            &function,
+            draw_mode,
+            &viewport,
+            max_iter,
+            &HashMap::new(),
+            &Palette::default(),
+        )
+        .unwrap()
+    }
+
+    fn pixel(pixels: &[u8], size: u32, row: u32, col: u32) -> [u8; 4] {
+        let k = 4 * (row * size + col) as usize;
+        pixels[k..k + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn domain_colors() {
+        assert_eq!(complex2rgb(Complex::from(0.0)), [0.0; 3]);
+        let [r, g, b] = complex2rgb(Complex::from(1.0));
+        assert!((r - 1.0 / 1.64872).abs() < 1e-6 && g == 0.0 && b == 0.0);
+        // positive reals are red, positive imaginaries yellow-green
+        assert_eq!(
+            render_str("1", DrawMode::DomainColouring, 2, 1),
+            [155, 0, 0, 255].repeat(4)
+        );
+        let pixels = render_str("z*i", DrawMode::DomainColouring, 5, 1);
+        assert_eq!(pixels.len(), 4 * 25);
+        assert!(pixel(&pixels, 5, 2, 2)[..3]
+            .iter()
+            .all(|&channel| channel < 32));
+        let [r, g, b, _] = pixel(&pixels, 5, 2, 4);
+        assert!(g > r && g > b, "{r} {g} {b}");
+    }
+
+    #[test]
+    fn center() {
+        let render_at = |input: &str, center| {
+            let viewport = Viewport {
+                center,
+                xscale: 1e-6,
+                width: 1,
+                height: 1,
+            };
+            let function = input.parse::<ExprComplex>().unwrap();
+            let draw_mode = DrawMode::DomainColouring;
+            render(
+                &function,
+                draw_mode,
+                &viewport,
+                1,
+                &HashMap::new(),
+                &Palette::default(),
+            )
+            .unwrap()
+        };
+        let center = Complex { re: -0.5, im: 2.0 };
+        assert_eq!(
+            render_at("z", center),
+            render_at("-0.5+2i", Complex::from(0.0))
+        );
+        assert_eq!(center_uniform(center_uniform(center)), center);
+    }
+
+    #[test]
+    fn max_iter() {
+        let viewport = Viewport {
+            center: Complex::from(0.0),
+            xscale: 2.0,
+            width: 2,
+            height: 2,
+        };
+        let function = "z^2+c".parse::<ExprComplex>().unwrap();
+        let draw_mode = DrawMode::ParameterStability;
+        let render_with = |max_iter| {
+            let palette = Palette::default();
+            render(
+                &function,
+                draw_mode,
+                &viewport,
+                max_iter,
+                &HashMap::new(),
+                &palette,
+            )
+        };
+        assert_eq!(render_with(0), Err(RenderError::MaxIter(0)));
+        assert_eq!(render_with(-5), Err(RenderError::MaxIter(-5)));
+        assert!(render_with(1).is_ok());
+    }
+
+    #[test]
+    fn mandelbrot() {
+        let pixels = render_str("z^2+c", DrawMode::ParameterStability, 9, 20);
+        // the center is in the set, the corners escape after a few iterations
+        assert_eq!(pixel(&pixels, 9, 4, 4), [0, 0, 0, 255]);
+        let [r, g, b, a] = pixel(&pixels, 9, 0, 0);
+        assert!(r > 0 && r < 64 && g == 0 && b == r && a == 255);
+        // symmetric about the real axis
+        for col in 0..9 {
+            assert_eq!(pixel(&pixels, 9, 1, col), pixel(&pixels, 9, 7, col));
+        }
+    }
+
+    #[test]
+    fn smooth_escape() {
+        let smoothing = |r: f32, degree| smoothing(Complex { re: 0.0, im: r }, degree);
+        assert_eq!(smoothing(10.0, Some(2)), 0.0);
+        assert!((smoothing(100.0, Some(2)) - 1.0).abs() < 1e-6);
+        assert!((smoothing(1000.0, Some(3)) - 1.0).abs() < 1e-6);
+        assert_eq!(smoothing(1000.0, None), 0.0);
+        assert_eq!(smoothing(1000.0, Some(1)), 0.0);
+        // no bands: along a ray that escapes, the colour changes gradually
+        let viewport = Viewport {
+            center: Complex::from(0.0),
+            xscale: 2.0,
+            width: 64,
+            height: 1,
+        };
+        let function = "z^2+c".parse::<ExprComplex>().unwrap();
+        let draw_mode = DrawMode::ParameterStability;
+        let pixels = render(
+            &function,
+            draw_mode,
+            &viewport,
+            40,
+            &HashMap::new(),
+            &Palette::default(),
+        )
+        .unwrap();
+        let reds: Vec<_> = pixels.iter().step_by(4).map(|&r| r as i32).collect();
+        for (left, right) in reds[40..].iter().zip(&reds[41..]) {
+            assert!(left >= right && left - right <= 4, "{reds:?}");
+        }
+    }
+
+    #[test]
+    fn julia_parameters() {
+        let viewport = Viewport {
+            center: Complex::from(0.0),
+            xscale: 2.0,
+            width: 8,
+            height: 4,
+        };
+        let function = "z^2+a*c".parse::<ExprComplex>().unwrap();
+        let mut parameters = HashMap::new();
+        parameters.insert("c".to_string(), Complex::from(0.25));
+        parameters.insert("a".to_string(), Complex::from(2.0));
+        let pixels = render(
+            &function,
+            DrawMode::Julia,
+            &viewport,
+            30,
+            &parameters,
+            &Palette::default(),
+        )
+        .unwrap();
+        assert_eq!(pixels.len(), 4 * 8 * 4);
+        let with_c = |c: f32| {
+            let function = format!("z^2+{c}").parse::<ExprComplex>().unwrap();
+            render(
+                &function,
+                DrawMode::Julia,
+                &viewport,
+                30,
+                &HashMap::new(),
+                &Palette::default(),
+            )
+            .unwrap()
+        };
+        assert_eq!(pixels, with_c(0.5));
+        assert_ne!(pixels, with_c(0.0));
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "webgl")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    algebra::Complex,
+    palette::Palette,
+    render::{check_max_iter, DrawMode, RenderError},
+};
+
+/// Everything needed to draw a view again, saved as JSON for links,
+/// bookmarks and bug reports. `center` and `xscale` are those of
+/// [`crate::render::Viewport`], the size of the canvas is not part of it.
+#[cfg_attr(feature = "webgl", wasm_bindgen(getter_with_clone))]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    /// Format of the scene, [`Scene::VERSION`] when saved by this version.
+    pub version: u32,
+    /// The source of the function, definitions included.
+    pub function: String,
+    pub draw_mode: DrawMode,
+    pub max_iter: i32,
+    pub xscale: f32,
+    #[cfg_attr(feature = "webgl", wasm_bindgen(skip))]
+    pub center: Complex<f32>,
+    #[cfg_attr(feature = "webgl", wasm_bindgen(skip))]
+    pub parameter_c: Complex<f32>,
+    /// Values of the free parameters by name, unset ones are 0.
+    #[cfg_attr(feature = "webgl", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub parameters: BTreeMap<String, Complex<f32>>,
+    /// Colours of the escape-time modes, the default one if left out.
+    #[cfg_attr(feature = "webgl", wasm_bindgen(skip))]
+    #[serde(default)]
+    pub palette: Palette,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneError {
+    /// Not JSON, or not a scene, with the message of the JSON parser.
+    Json(String),
+    /// Saved by a later version, in a format this one does not know.
+    UnsupportedVersion(u32),
+    /// A view that cannot be drawn, e.g. with no iterations.
+    Render(RenderError),
+}
+
+impl std::fmt::Display for SceneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SceneError::Json(message) => write!(f, "invalid scene: {message}"),
+            SceneError::UnsupportedVersion(version) => write!(
+                f,
+                "scene version {version} is newer than the supported {}",
+                Scene::VERSION
+            ),
+            SceneError::Render(err) => write!(f, "invalid scene: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "webgl")]
+impl From<SceneError> for JsValue {
+    fn from(err: SceneError) -> Self {
+        err.to_string().into()
+    }
+}
+
+impl Scene {
+    pub const VERSION: u32 = 1;
+}
+
+#[cfg_attr(feature = "webgl", wasm_bindgen)]
+impl Scene {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a scene is always valid JSON")
+    }
+
+    pub fn from_json(json: &str) -> Result<Scene, SceneError> {
+        // the version is checked first, a later format may not parse as this one
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+        let json_error = |err: serde_json::Error| SceneError::Json(err.to_string());
+        let Versioned { version } = serde_json::from_str(json).map_err(json_error)?;
+        if version > Scene::VERSION {
+            return Err(SceneError::UnsupportedVersion(version));
+        }
+        let scene: Scene = serde_json::from_str(json).map_err(json_error)?;
+        check_max_iter(scene.max_iter).map_err(SceneError::Render)?;
+        Ok(scene)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{Scene, SceneError};
+    use crate::{
+        algebra::Complex,
+        palette::Palette,
+        render::{DrawMode, RenderError},
+    };
+
+    fn scene() -> Scene {
+        let mut parameters = BTreeMap::new();
+        parameters.insert("a".to_string(), Complex { re: 1.0, im: -0.5 });
+        Scene {
+            version: Scene::VERSION,
+            function: "g(w) = w*w; g(z)+a*c".to_string(),
+            draw_mode: DrawMode::ParameterStability,
+            max_iter: 80,
+            xscale: 0.125,
+            center: Complex { re: 0.75, im: 0.1 },
+            parameter_c: Complex { re: -0.4, im: 0.6 },
+            parameters,
+            palette: Palette::named("grayscale").unwrap(),
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = scene().to_json();
+        assert_eq!(
+            json,
+            r#"{"version":1,"function":"g(w) = w*w; g(z)+a*c","draw_mode":"parameter_stability","max_iter":80,"xscale":0.125,"center":{"re":0.75,"im":0.1},"parameter_c":{"re":-0.4,"im":0.6},"parameters":{"a":{"re":1.0,"im":-0.5}},"palette":{"stops":[{"position":0.0,"color":[0.0,0.0,0.0]},{"position":1.0,"color":[1.0,1.0,1.0]}],"scale":"linear","cyclic":false,"offset":0.0,"density":1.0}}"#
+        );
+        assert_eq!(Scene::from_json(&json), Ok(scene()));
+        // parameters and palette may be left out
+        let json = json.replace(r#","parameters":{"a":{"re":1.0,"im":-0.5}}"#, "");
+        let json = json.split(r#","palette""#).next().unwrap().to_string() + "}";
+        let expected = Scene {
+            parameters: BTreeMap::new(),
+            palette: Palette::default(),
+            ..scene()
+        };
+        assert_eq!(Scene::from_json(&json), Ok(expected));
+    }
+
+    #[test]
+    fn json_errors() {
+        let json = scene()
+            .to_json()
+            .replace(r#""version":1"#, r#""version":2"#);
+        assert_eq!(
+            Scene::from_json(&json),
+            Err(SceneError::UnsupportedVersion(2))
+        );
+        let json = scene().to_json().replace("parameter_stability", "fractal");
+        assert!(matches!(Scene::from_json(&json), Err(SceneError::Json(_))));
+        for max_iter in [0, -5] {
+            let json = scene()
+                .to_json()
+                .replace(r#""max_iter":80"#, &format!(r#""max_iter":{max_iter}"#));
+            assert_eq!(
+                Scene::from_json(&json),
+                Err(SceneError::Render(RenderError::MaxIter(max_iter)))
+            );
+        }
+        let err = Scene::from_json(r#"{"function":"z"}"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid scene: missing field `version` at line 1 column 16"
+        );
+    }
+}
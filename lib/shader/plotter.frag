@@ -1,9 +1,16 @@
 #version 300 es
 
-precision mediump float;
+/*BEGIN PRECISION*/precision mediump float;/*END PRECISION*/
 
 #define E_SQRT 1.64872
 #define TAU 6.28318530718
+#define ESCAPE_RADIUS 10.0
+#define INFINITY uintBitsToFloat(0x7f800000u)
+#define NAN uintBitsToFloat(0x7fc00000u)
+// degree of f in z, 0.0 if it is not a polynomial
+#define DEGREE /*BEGIN DEGREE*/0.0/*END DEGREE*/
+// MAX_STOPS in palette.rs
+#define PALETTE_SIZE 16
 
 in vec2 st;
 out vec4 fragColor;
@@ -16,6 +23,14 @@ uniform vec2 center;
 uniform vec2 parameter_c;
 uniform int n_subsample;
 
+// the gradient of the escape-time modes, see palette.rs
+uniform vec4 palette[PALETTE_SIZE]; // rgb and position of each stop
+uniform int palette_len;
+uniform bool palette_log;
+uniform bool palette_cyclic;
+uniform float palette_offset;
+uniform float palette_density;
+
 vec3 complex2rgb(vec2 z) {
     float r = length(z);
     float h = atan(z.y, z.x) / TAU;
@@ -50,6 +65,14 @@ vec2 div(vec2 z, vec2 w) {
     ) / dot(w, w);
 }
 
+vec2 neg(vec2 z) {
+    return -z;
+}
+
+vec2 inv(vec2 z) {
+    return vec2(z.x, -z.y) / dot(z, z);
+}
+
 vec2 re(vec2 z) {
     return vec2(z.x, 0.0);
 }
@@ -58,8 +81,112 @@ vec2 im(vec2 z) {
     return vec2(z.y, 0.0);
 }
 
+vec2 cabs(vec2 z) {
+    return vec2(length(z), 0.0);
+}
+
+// Transcendental functions, principal branches as in algebra.rs
+
+vec2 cexp(vec2 z) {
+    return exp(z.x) * vec2(cos(z.y), sin(z.y));
+}
+
+vec2 clog(vec2 z) {
+    return vec2(log(length(z)), atan(z.y, z.x));
+}
+
+vec2 csqrt(vec2 z) {
+    float r = length(z);
+    float im = sqrt(0.5 * (r - z.x));
+    return vec2(sqrt(0.5 * (r + z.x)), z.y < 0.0 ? -im : im);
+}
+
+vec2 csin(vec2 z) {
+    return vec2(sin(z.x) * cosh(z.y), cos(z.x) * sinh(z.y));
+}
+
+vec2 ccos(vec2 z) {
+    return vec2(cos(z.x) * cosh(z.y), -sin(z.x) * sinh(z.y));
+}
+
+vec2 ctan(vec2 z) {
+    vec2 w = 2.0 * z;
+    return vec2(sin(w.x), sinh(w.y)) / (cos(w.x) + cosh(w.y));
+}
+
+vec2 csinh(vec2 z) {
+    return vec2(sinh(z.x) * cos(z.y), cosh(z.x) * sin(z.y));
+}
+
+vec2 ccosh(vec2 z) {
+    return vec2(cosh(z.x) * cos(z.y), sinh(z.x) * sin(z.y));
+}
+
+vec2 ctanh(vec2 z) {
+    vec2 w = 2.0 * z;
+    return vec2(sinh(w.x), sin(w.y)) / (cosh(w.x) + cos(w.y));
+}
+
+vec2 casin(vec2 z) {
+    // -i log(iz + sqrt(1 - z^2))
+    vec2 w = clog(vec2(-z.y, z.x) + csqrt(vec2(1.0, 0.0) - mul(z, z)));
+    return vec2(w.y, -w.x);
+}
+
+vec2 catan(vec2 z) {
+    // i/2 (log(1 - iz) - log(1 + iz))
+    vec2 iz = vec2(-z.y, z.x);
+    vec2 w = clog(vec2(1.0, 0.0) - iz) - clog(vec2(1.0, 0.0) + iz);
+    return 0.5 * vec2(-w.y, w.x);
+}
+
+// z^n by repeated squaring, infinite for 0^n with n < 0 as in cpow
+vec2 cpowi(vec2 z, int n) {
+    if (n < 0 && z == vec2(0.0)) {
+        return vec2(INFINITY, 0.0);
+    }
+    vec2 base = n < 0 ? inv(z) : z;
+    vec2 acc = vec2(1.0, 0.0);
+    for (int e = abs(n); e > 0; e >>= 1) {
+        if ((e & 1) == 1) {
+            acc = mul(acc, base);
+        }
+        base = mul(base, base);
+    }
+    return acc;
+}
+
+vec2 cpow(vec2 z, vec2 w) {
+    if (z == vec2(0.0)) {
+        if (w == vec2(0.0)) {
+            return vec2(1.0, 0.0);
+        }
+        return vec2(w.x > 0.0 ? 0.0 : w.x < 0.0 ? INFINITY : NAN, 0.0);
+    }
+    return cexp(mul(w, clog(z)));
+}
+
+// Comparisons of the real parts, 1 if true and 0 otherwise
+vec2 clt(vec2 z, vec2 w) {
+    return vec2(z.x < w.x ? 1.0 : 0.0, 0.0);
+}
+
+vec2 cle(vec2 z, vec2 w) {
+    return vec2(z.x <= w.x ? 1.0 : 0.0, 0.0);
+}
+
+vec2 cgt(vec2 z, vec2 w) {
+    return vec2(z.x > w.x ? 1.0 : 0.0, 0.0);
+}
+
+vec2 cge(vec2 z, vec2 w) {
+    return vec2(z.x >= w.x ? 1.0 : 0.0, 0.0);
+}
+
+// uniforms for the parameters of f and its helper functions
+/*BEGIN DEFINITIONS*//*END DEFINITIONS*/
 vec2 f(vec2 z, vec2 c) {
-    return /*BEGIN REPLACE*/ z /*END REPLACE*/;
+    /*BEGIN REPLACE*/return z;/*END REPLACE*/
 }
 
 vec3 domain_color(vec2 z) {
@@ -67,33 +194,59 @@ vec3 domain_color(vec2 z) {
     return complex2rgb(fz);
 }
 
+// Fraction of an iteration to take off the escape time, 0 at |z| = R and 1 at
+// R^DEGREE so that it varies continuously, as `smoothing` in render.rs
+float smoothing(vec2 z) {
+    return DEGREE > 1.0 ? log(log(length(z)) / log(ESCAPE_RADIUS)) / log(DEGREE) : 0.0;
+}
+
+// Color of an orbit that escaped after n iterations, n smoothed, as
+// `Palette::color` in palette.rs
+vec3 palette_color(float n) {
+    n = clamp(n, 0.0, float(max_iter));
+    float t = palette_log ?
+        log(1.0 + n) / log(1.0 + float(max_iter)) :
+        n / float(max_iter);
+    t = palette_offset + palette_density * t;
+    t = palette_cyclic ? fract(t) : clamp(t, 0.0, 1.0);
+
+    vec4 prev = palette[0];
+    if (t <= prev.w) {
+        return prev.rgb;
+    }
+    for (int k = 1; k < palette_len; ++k) {
+        vec4 next = palette[k];
+        if (t <= next.w) {
+            return mix(prev.rgb, next.rgb, (t - prev.w) / (next.w - prev.w));
+        }
+        prev = next;
+    }
+    return prev.rgb;
+}
+
 vec3 iter_color(vec2 c) {
     vec2 z = vec2(0.0, 0.0);
     int i = 0;
-    while(length(z) < 10.0 && ++i < max_iter) {
+    while(length(z) < ESCAPE_RADIUS && ++i < max_iter) {
         z = f(z, c);
     }
     if (i == max_iter) {
         return vec3(0.0);
     } else {
-        float smoothed = 0.0; // log(log(length(z))) / log(2.0);
-        float stab = clamp((float(i) - smoothed) / float(max_iter), 0.0, 1.0);
-        return vec3(stab, 0.0, stab);
+        return palette_color(float(i) - smoothing(z));
     } 
 }
 
 
 vec3 julia_color(vec2 z) {
     int i = 0;
-    while(length(z) < 10.0 && ++i < max_iter) {
+    while(length(z) < ESCAPE_RADIUS && ++i < max_iter) {
         z = f(z, parameter_c);
     }
     if (i == max_iter) {
         return vec3(0.0);
     } else {
-        float smoothed = 0.0; // log(log(length(z))) / log(2.0);
-        float stab = clamp((float(i) - smoothed) / float(max_iter), 0.0, 1.0);
-        return vec3(stab, 0.0, stab);
+        return palette_color(float(i) - smoothing(z));
     } 
 }
 
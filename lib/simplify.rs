@@ -0,0 +1,228 @@
+use std::{cmp::Ordering, collections::HashMap, rc::Rc};
+
+use crate::{
+    algebra::{One, Zero},
+    expression::{AsField, Definition, Expr, FieldFunction, FieldOperator, Function, Operator},
+};
+
+impl<T, F, O> Expr<T, F, O>
+where
+    T: Clone + PartialEq + Zero + One,
+    F: Clone + PartialEq + Function<T> + AsField<FieldFunction>,
+    O: Clone + PartialEq + Operator<T> + AsField<FieldOperator>,
+{
+    /// Bottom-up simplification: constant subtrees are folded, field
+    /// identities (`x+0`, `x*1`, `x*0`, `--x`, `inv(inv(x))`...) are removed
+    /// and the operands of `+` and `*` are put in canonical order, constants
+    /// first, so that `z*2` and `2*z` simplify to the same tree.
+    ///
+    /// Lets and calls are kept, with their parts simplified, and an `if` with
+    /// a constant condition is replaced by its branch.
+    ///
+    /// The result evaluates to the same value up to floating point rounding,
+    /// except that `x*0` is `0` even where `x` is not finite.
+    pub fn simplify(&self) -> Self {
+        self.simplify_in(&mut HashMap::new())
+    }
+
+    /// `simplified` holds the simplified definitions, so that each is
+    /// simplified once and stays shared by its calls.
+    fn simplify_in(&self, simplified: &mut Simplified<T, F, O>) -> Self {
+        match self {
+            Expr::Variable(_) | Expr::Constant(_) => self.clone(),
+            Expr::Function(fun, e) => simplify_function(fun.clone(), e.simplify_in(simplified)),
+            Expr::Operator(op, left, right) => simplify_operator(
+                op.clone(),
+                left.simplify_in(simplified),
+                right.simplify_in(simplified),
+            ),
+            Expr::Let(var, e, body) => Expr::let_in(
+                var.clone(),
+                e.simplify_in(simplified),
+                body.simplify_in(simplified),
+            ),
+            Expr::If(cond, then, otherwise) => match cond.simplify_in(simplified) {
+                Expr::Constant(ct) if ct == T::O => otherwise.simplify_in(simplified),
+                Expr::Constant(_) => then.simplify_in(simplified),
+                cond => Expr::if_else(
+                    cond,
+                    then.simplify_in(simplified),
+                    otherwise.simplify_in(simplified),
+                ),
+            },
+            Expr::Call(def, args) => {
+                let key = Rc::as_ptr(def);
+                let def = match simplified.get(&key) {
+                    Some(def) => def.clone(),
+                    None => {
+                        let body = def.body.simplify_in(simplified);
+                        let def = Rc::new(Definition {
+                            name: def.name.clone(),
+                            params: def.params.clone(),
+                            body,
+                        });
+                        simplified.insert(key, def.clone());
+                        def
+                    }
+                };
+                let args = args.iter().map(|arg| arg.simplify_in(simplified)).collect();
+                Expr::Call(def, args)
+            }
+        }
+    }
+}
+
+/// The simplified definition of each definition met so far, by address.
+type Simplified<T, F, O> = HashMap<*const Definition<T, F, O>, Rc<Definition<T, F, O>>>;
+
+fn simplify_function<T, F, O>(fun: F, e: Expr<T, F, O>) -> Expr<T, F, O>
+where
+    F: PartialEq + Function<T> + AsField<FieldFunction>,
+{
+    match e {
+        Expr::Constant(ct) => Expr::Constant(fun.apply(ct)),
+        // negation and inversion are involutions
+        Expr::Function(inner, e) if fun.as_field().is_some() && inner == fun => *e,
+        e => Expr::function(fun, e),
+    }
+}
+
+fn simplify_operator<T, F, O>(op: O, left: Expr<T, F, O>, right: Expr<T, F, O>) -> Expr<T, F, O>
+where
+    T: Clone + PartialEq + Zero + One,
+    F: Clone + PartialEq + AsField<FieldFunction>,
+    O: Clone + PartialEq + Operator<T> + AsField<FieldOperator>,
+{
+    use FieldOperator::*;
+    if let (Expr::Constant(a), Expr::Constant(b)) = (&left, &right) {
+        return Expr::Constant(op.apply(a.clone(), b.clone()));
+    }
+    let (zero, one) = (Expr::Constant(T::O), Expr::Constant(T::U));
+    match op.as_field() {
+        Some(Add | Mul) => {
+            let (left, right) = match canonical_order(&left, &right) {
+                Ordering::Greater => (right, left),
+                _ => (left, right),
+            };
+            let is_add = op.as_field() == Some(&Add);
+            let neutral = if is_add { zero.clone() } else { one };
+            if left == neutral {
+                return right;
+            }
+            if !is_add && left == zero {
+                return left;
+            }
+            match (left, right) {
+                // c1 op (c2 op x) = (c1 op c2) op x
+                (Expr::Constant(a), Expr::Operator(inner, b, x)) if inner == op => match *b {
+                    Expr::Constant(b) => {
+                        let folded = Expr::Constant(op.apply(a, b));
+                        simplify_operator(op, folded, *x)
+                    }
+                    b => {
+                        Expr::operator(op, Expr::Constant(a), Expr::Operator(inner, Box::new(b), x))
+                    }
+                },
+                (left, right) => Expr::operator(op, left, right),
+            }
+        }
+        Some(Sub) if right == zero => left,
+        Some(Sub) if left == zero => Expr::function(FieldFunction::Neg, right),
+        Some(Div) if right == one => left,
+        Some(Div) if left == one => Expr::function(FieldFunction::Inv, right),
+        _ => Expr::operator(op, left, right),
+    }
+}
+
+/// Constants, then variables, then compound expressions. Compound
+/// expressions compare equal, so their relative order is kept.
+fn canonical_order<T, F, O>(a: &Expr<T, F, O>, b: &Expr<T, F, O>) -> Ordering {
+    fn rank<T, F, O>(e: &Expr<T, F, O>) -> u8 {
+        match e {
+            Expr::Constant(_) => 0,
+            Expr::Variable(_) => 1,
+            Expr::Function(..)
+            | Expr::Operator(..)
+            | Expr::Let(..)
+            | Expr::Call(..)
+            | Expr::If(..) => 2,
+        }
+    }
+    match (a, b) {
+        (Expr::Variable(a), Expr::Variable(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        algebra::Complex,
+        expression::{call_chain, Expr, ExprComplex, FieldFunction, FieldOperator, Variable},
+    };
+
+    type ExprReal = Expr<f32, FieldFunction, FieldOperator>;
+
+    fn simplified(input: &str) -> ExprReal {
+        input.parse::<ExprReal>().unwrap().simplify()
+    }
+
+    fn parsed(input: &str) -> ExprReal {
+        input.parse::<ExprReal>().unwrap()
+    }
+
+    #[test]
+    fn constant_folding() {
+        assert_eq!(simplified("2*3*z+0"), parsed("6*z"));
+        assert_eq!(simplified("(1+2)/(4-1)"), Expr::Constant(1.0));
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(simplified("0+z"), Expr::Variable(Variable::Z));
+        assert_eq!(simplified("z-0"), Expr::Variable(Variable::Z));
+        assert_eq!(simplified("1*z/1"), Expr::Variable(Variable::Z));
+        assert_eq!(simplified("(z+c)*0"), Expr::Constant(0.0));
+        assert_eq!(simplified("0-z"), parsed("-z"));
+        assert_eq!(simplified("1/z"), parsed("inv(z)"));
+    }
+
+    #[test]
+    fn involutions() {
+        assert_eq!(simplified("--z"), Expr::Variable(Variable::Z));
+        assert_eq!(simplified("inv(inv(z))"), Expr::Variable(Variable::Z));
+        assert_eq!(simplified("-inv(-z)"), parsed("-inv(-z)"));
+    }
+
+    #[test]
+    fn canonical_order() {
+        assert_eq!(simplified("z*2"), simplified("2*z"));
+        assert_eq!(simplified("c+z"), simplified("z+c"));
+        assert_eq!(simplified("2*z*3"), parsed("6*z"));
+        assert_eq!(simplified("1+z+2"), parsed("3+z"));
+        assert_eq!(simplified("z-2*3"), parsed("z-6"));
+    }
+
+    #[test]
+    fn complex() {
+        let expr = "(2-i)*(2+i)*z".parse::<ExprComplex>().unwrap();
+        assert_eq!(
+            expr.simplify(),
+            Expr::operator(
+                FieldOperator::Mul,
+                Expr::Constant(Complex::from(5.0)),
+                Expr::Variable(Variable::Z),
+            )
+        );
+    }
+
+    #[test]
+    fn definitions_simplified_once() {
+        let program = call_chain(40, "w*1+0", |g| format!("{g}(w)+{g}(w*1)"), "z");
+        let expr = program.parse::<ExprComplex>().unwrap().simplify();
+        let defs = expr.definitions();
+        assert_eq!(defs.len(), 40);
+        assert_eq!(defs[0].to_string(), "g0(w) = w");
+        assert_eq!(defs[1].to_string(), "g1(w) = g0(w)+g0(w)");
+    }
+}